@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::Range;
+use crate::disassembler::{disassemble_at, Line};
+use crate::instruction::Instruction;
+use crate::ram::Ram;
+
+// How control leaves a basic block
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Exit {
+    // Runs into the next block, which something else jumps to
+    Fallthrough,
+    Jump,
+    // Either the next instruction or the one after it
+    Skip,
+    Return,
+    // SUPER-CHIP's 00FD
+    Halt,
+    // Bnnn, whose target depends on a register
+    Computed,
+    // An opcode that doesn't decode, or the end of memory
+    Invalid,
+}
+
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub start: u16,
+    pub lines: Vec<Line>,
+    pub successors: Vec<u16>,
+    pub exit: Exit,
+}
+
+impl Block {
+    // Address right after the last instruction, may be past the end of memory
+    pub fn end(&self) -> u32 {
+        self.lines.last().map_or(self.start as u32, |line| line.address as u32 + line.size() as u32)
+    }
+}
+
+// Code reachable from an entry point that isn't a call target is part of
+// that entry's function
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub entry: u16,
+    pub blocks: BTreeSet<u16>,
+    pub callees: BTreeSet<u16>,
+}
+
+pub struct Analysis {
+    blocks: BTreeMap<u16, Block>,
+    functions: BTreeMap<u16, Function>,
+    // Whether each byte of memory belongs to a reachable instruction
+    code: Vec<bool>,
+}
+
+// Follows control flow from the program start to tell code from data
+pub fn analyze(ram: &Ram) -> Analysis {
+    analyze_from(ram, &[ram.program_start()])
+}
+
+// Extra entries let targets of computed jumps be analyzed too
+pub fn analyze_from(ram: &Ram, entries: &[u16]) -> Analysis {
+    let mut lines: BTreeMap<u16, Line> = BTreeMap::new();
+    let mut leaders: BTreeSet<u16> = entries.iter().copied().collect();
+    let mut function_entries = leaders.clone();
+    let mut pending: Vec<u16> = entries.to_vec();
+
+    while let Some(address) = pending.pop() {
+        if lines.contains_key(&address) {
+            continue;
+        }
+        let line = match disassemble_at(ram, address) {
+            Some(line) => line,
+            None => continue,
+        };
+        let (successors, exit) = flow(&line, ram);
+        if let Some(Instruction::Call(target)) = line.instruction {
+            leaders.insert(target);
+            function_entries.insert(target);
+            pending.push(target);
+        }
+        if exit.is_some() {
+            leaders.extend(&successors);
+        }
+        pending.extend(successors);
+        lines.insert(address, line);
+    }
+
+    let mut code = vec![false; ram.size()];
+    for line in lines.values() {
+        for offset in 0..line.size() {
+            code[line.address.wrapping_add(offset) as usize % ram.size()] = true;
+        }
+    }
+
+    let mut blocks = BTreeMap::new();
+    for &leader in leaders.iter().filter(|leader| lines.contains_key(leader)) {
+        let mut block = Block { start: leader, lines: Vec::new(), successors: Vec::new(), exit: Exit::Invalid };
+        let mut address = leader;
+        loop {
+            let line = lines[&address].clone();
+            let (successors, exit) = flow(&line, ram);
+            block.lines.push(line);
+            if let Some(exit) = exit {
+                block.successors = successors;
+                block.exit = exit;
+                break;
+            }
+            let next = successors[0];
+            if leaders.contains(&next) || !lines.contains_key(&next) {
+                block.successors = successors;
+                block.exit = if lines.contains_key(&next) { Exit::Fallthrough } else { Exit::Invalid };
+                break;
+            }
+            address = next;
+        }
+        blocks.insert(leader, block);
+    }
+
+    let functions = function_entries.iter()
+        .filter(|entry| blocks.contains_key(entry))
+        .map(|&entry| (entry, function(&blocks, entry)))
+        .collect();
+
+    Analysis { blocks, functions, code }
+}
+
+// Where execution can go after the instruction, and how it leaves the block
+// if it has to end one
+fn flow(line: &Line, ram: &Ram) -> (Vec<u16>, Option<Exit>) {
+    let next = line.address.wrapping_add(line.size());
+    let instruction = match line.instruction {
+        Some(instruction) => instruction,
+        None => return (Vec::new(), Some(Exit::Invalid)),
+    };
+    match instruction {
+        Instruction::Jump(target) => (vec![target], Some(Exit::Jump)),
+        Instruction::Return => (Vec::new(), Some(Exit::Return)),
+        Instruction::Exit => (Vec::new(), Some(Exit::Halt)),
+        Instruction::JumpWithOffset(_) => (Vec::new(), Some(Exit::Computed)),
+        Instruction::SkipIfEqualsByte(..)
+        | Instruction::SkipIfNotEqualsByte(..)
+        | Instruction::SkipIfEqualsRegister(..)
+        | Instruction::SkipIfNotEqualsRegister(..)
+        | Instruction::SkipIfPressed(_)
+        | Instruction::SkipIfNotPressed(_) => {
+            // Skipping over XO-CHIP's long load takes both of its words
+            let skipped = ram.read_word(next).map_or(2, Instruction::size);
+            (vec![next, next.wrapping_add(skipped)], Some(Exit::Skip))
+        }
+        _ => (vec![next], None),
+    }
+}
+
+fn function(blocks: &BTreeMap<u16, Block>, entry: u16) -> Function {
+    let mut function = Function { entry, blocks: BTreeSet::new(), callees: BTreeSet::new() };
+    let mut pending = vec![entry];
+    while let Some(start) = pending.pop() {
+        let block = match blocks.get(&start) {
+            Some(block) => block,
+            None => continue,
+        };
+        if !function.blocks.insert(start) {
+            continue;
+        }
+        for line in &block.lines {
+            if let Some(Instruction::Call(target)) = line.instruction {
+                function.callees.insert(target);
+            }
+        }
+        pending.extend(&block.successors);
+    }
+    function
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Analysis {
+    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.values()
+    }
+
+    pub fn block(&self, start: u16) -> Option<&Block> {
+        self.blocks.get(&start)
+    }
+
+    // The block holding the instruction that starts at the address
+    pub fn block_containing(&self, address: u16) -> Option<&Block> {
+        let (_, block) = self.blocks.range(..=address).next_back()?;
+        block.lines.iter().any(|line| line.address == address).then_some(block)
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = &Function> {
+        self.functions.values()
+    }
+
+    pub fn function(&self, entry: u16) -> Option<&Function> {
+        self.functions.get(&entry)
+    }
+
+    pub fn is_code(&self, address: u16) -> bool {
+        self.code.get(address as usize).copied().unwrap_or(false)
+    }
+
+    // Runs of bytes in the range that no reachable instruction covers
+    pub fn data_ranges(&self, range: Range<usize>) -> Vec<Range<usize>> {
+        let end = range.end.min(self.code.len());
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for address in range.start..end {
+            if self.code[address] {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.end == address => last.end += 1,
+                _ => ranges.push(address..address + 1),
+            }
+        }
+        ranges
+    }
+
+    // Control-flow graph with a node per basic block listing its instructions
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            for line in &block.lines {
+                label.push_str(&escape(&line.to_string()));
+                label.push_str("\\l");
+            }
+            let _ = writeln!(dot, "    \"{:04X}\" [label=\"{}\"];", block.start, label);
+        }
+        for block in self.blocks.values() {
+            for (i, successor) in block.successors.iter().enumerate() {
+                if !self.blocks.contains_key(successor) {
+                    continue;
+                }
+                let attributes = if block.exit == Exit::Skip && i == 1 { " [label=\"skip\"]" } else { "" };
+                let _ = writeln!(dot, "    \"{:04X}\" -> \"{:04X}\"{};", block.start, successor, attributes);
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn call_graph_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n    node [shape=ellipse, fontname=\"monospace\"];\n");
+        for function in self.functions.values() {
+            let _ = writeln!(dot, "    \"{:04X}\" [label=\"sub_{:04X}\"];", function.entry, function.entry);
+        }
+        for function in self.functions.values() {
+            for callee in &function.callees {
+                let _ = writeln!(dot, "    \"{:04X}\" -> \"{:04X}\";", function.entry, callee);
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{analyze, analyze_from, Exit};
+    use crate::assembler::assemble;
+    use crate::ram::Ram;
+
+    fn ram(source: &str) -> Ram {
+        let rom = assemble(source).expect("Error assembling program");
+        Ram::from_bytes(&rom).expect("Error loading ROM")
+    }
+
+    const PROGRAM: &str = "
+        main:   call draw           ; 0x200
+        loop:   se v0, 1            ; 0x202
+                jp loop             ; 0x204
+                jp main             ; 0x206
+        draw:   ld i, ball          ; 0x208
+                drw v0, v1, 1       ; 0x20A
+                ret                 ; 0x20C
+        ball:   db 0x80, 0xFF       ; 0x20E
+    ";
+
+    #[test]
+    fn test_analyze_blocks() {
+        let analysis = analyze(&ram(PROGRAM));
+        let starts: Vec<u16> = analysis.blocks().map(|block| block.start).collect();
+        assert_eq!(starts, [0x200, 0x202, 0x204, 0x206, 0x208]);
+
+        let main = analysis.block(0x200).expect("Missing block");
+        assert_eq!(main.exit, Exit::Fallthrough);
+        assert_eq!(main.successors, [0x202]);
+        let skip = analysis.block(0x202).expect("Missing block");
+        assert_eq!(skip.exit, Exit::Skip);
+        assert_eq!(skip.successors, [0x204, 0x206]);
+        let draw = analysis.block(0x208).expect("Missing block");
+        assert_eq!(draw.exit, Exit::Return);
+        assert_eq!(draw.lines.len(), 3);
+        assert_eq!(draw.end(), 0x20E);
+        assert_eq!(analysis.block_containing(0x20A).map(|block| block.start), Some(0x208));
+    }
+
+    #[test]
+    fn test_analyze_code_and_data() {
+        let analysis = analyze(&ram(PROGRAM));
+        assert!(analysis.is_code(0x200));
+        assert!(analysis.is_code(0x20D));
+        assert!(!analysis.is_code(0x20E));
+        let ranges = analysis.data_ranges(0x200..0x210);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0], 0x20E..0x210);
+    }
+
+    #[test]
+    fn test_analyze_call_graph() {
+        let analysis = analyze(&ram(PROGRAM));
+        let entries: Vec<u16> = analysis.functions().map(|function| function.entry).collect();
+        assert_eq!(entries, [0x200, 0x208]);
+        let main = analysis.function(0x200).expect("Missing function");
+        assert!(main.callees.contains(&0x208));
+        assert!(!main.blocks.contains(&0x208));
+        assert!(analysis.function(0x208).expect("Missing function").callees.is_empty());
+    }
+
+    #[test]
+    fn test_analyze_skip_over_long_instruction() {
+        let analysis = analyze(&ram("sne v0, 0\nld i, long 0x1234\nexit"));
+        let skip = analysis.block(0x200).expect("Missing block");
+        assert_eq!(skip.successors, [0x202, 0x206]);
+        assert_eq!(analysis.block(0x206).expect("Missing block").exit, Exit::Halt);
+    }
+
+    #[test]
+    fn test_analyze_computed_jump() {
+        let ram = ram("jp v0, table\ntable: jp 0x200\njp 0x200");
+        let analysis = analyze(&ram);
+        assert_eq!(analysis.block(0x200).expect("Missing block").exit, Exit::Computed);
+        assert!(!analysis.is_code(0x202));
+        let analysis = analyze_from(&ram, &[0x200, 0x202, 0x204]);
+        assert!(analysis.is_code(0x204));
+    }
+
+    #[test]
+    fn test_analyze_invalid_opcode() {
+        let analysis = analyze(&ram("ld v0, 1\ndw 0x800F"));
+        assert_eq!(analysis.block(0x200).expect("Missing block").exit, Exit::Invalid);
+    }
+
+    #[test]
+    fn test_analyze_end_of_memory() {
+        let ram = Ram::from_bytes(&[0x1F, 0xFE]).expect("Error loading ROM");
+        let analysis = analyze(&ram);
+        assert!(analysis.is_code(0xFFF));
+        let last = analysis.block(0xFFE).expect("Missing block");
+        assert_eq!(last.exit, Exit::Invalid);
+        assert_eq!(last.end(), 0x1000);
+    }
+
+    #[test]
+    fn test_dot() {
+        let analysis = analyze(&ram(PROGRAM));
+        let dot = analysis.to_dot();
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("\"0208\" [label=\"0208  A20E       LD I, 0x20E\\l"));
+        assert!(dot.contains("\"0202\" -> \"0206\" [label=\"skip\"];"));
+        assert!(dot.contains("\"0200\" -> \"0202\";"));
+        let calls = analysis.call_graph_dot();
+        assert!(calls.contains("\"0200\" -> \"0208\";"));
+    }
+}
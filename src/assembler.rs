@@ -0,0 +1,777 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::instruction::Instruction;
+use crate::ram::PROGRAM_START;
+
+// Deepest chain of includes before giving up, which also catches cycles
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    UnexpectedCharacter(char),
+    UnterminatedString,
+    InvalidNumber(String),
+    UnexpectedToken,
+    ExpectedOperand,
+    ExpectedExpression,
+    UnknownMnemonic(String),
+    InvalidOperands(String),
+    UndefinedSymbol(String),
+    DuplicateSymbol(String),
+    ReservedName(String),
+    OutOfRange { value: i64, min: i64, max: i64 },
+    InvalidSprite(char),
+    SpriteTooWide(usize),
+    Include { path: PathBuf, error: io::Error },
+    IncludeTooDeep,
+    ProgramTooLarge,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UnexpectedCharacter(c) => write!(f, "unexpected character '{}'", c),
+            ErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            ErrorKind::InvalidNumber(number) => write!(f, "invalid number '{}'", number),
+            ErrorKind::UnexpectedToken => write!(f, "unexpected token"),
+            ErrorKind::ExpectedOperand => write!(f, "expected an operand"),
+            ErrorKind::ExpectedExpression => write!(f, "expected a number or symbol"),
+            ErrorKind::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic '{}'", mnemonic),
+            ErrorKind::InvalidOperands(mnemonic) => write!(f, "invalid operands for {}", mnemonic),
+            ErrorKind::UndefinedSymbol(name) => write!(f, "undefined symbol '{}'", name),
+            ErrorKind::DuplicateSymbol(name) => write!(f, "symbol '{}' already defined", name),
+            ErrorKind::ReservedName(name) => write!(f, "'{}' is a reserved name", name),
+            ErrorKind::OutOfRange { value, min, max } => {
+                write!(f, "value {} out of range {}..={}", value, min, max)
+            }
+            ErrorKind::InvalidSprite(c) => write!(f, "invalid sprite pixel '{}'", c),
+            ErrorKind::SpriteTooWide(width) => write!(f, "sprite row {} pixels wide, at most 16", width),
+            ErrorKind::Include { path, error } => write!(f, "cannot include {}: {}", path.display(), error),
+            ErrorKind::IncludeTooDeep => write!(f, "includes nested more than {} deep", MAX_INCLUDE_DEPTH),
+            ErrorKind::ProgramTooLarge => write!(f, "program runs past the end of memory"),
+        }
+    }
+}
+
+// Errors point at the file, line and column they come from, all 1-based.
+// Failing to read the top level file has line 0.
+#[derive(Debug)]
+pub struct AssemblyError {
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), 0) => write!(f, "{}: {}", file.display(), self.kind),
+            (Some(file), _) => write!(f, "{}:{}:{}: {}", file.display(), self.line, self.column, self.kind),
+            (None, _) => write!(f, "line {}, column {}: {}", self.line, self.column, self.kind),
+        }
+    }
+}
+
+impl Error for AssemblyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Include { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// Assembles source text into a ROM image to be loaded at 0x200. Includes are
+// resolved relative to the current directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblyError> {
+    let mut assembler = Assembler::new();
+    assembler.parse(source, None, Path::new(""), 0)?;
+    assembler.emit()
+}
+
+// Includes are resolved relative to the directory of the including file
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, AssemblyError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|error| AssemblyError {
+        file: Some(path.to_path_buf()),
+        line: 0,
+        column: 0,
+        kind: ErrorKind::Include { path: path.to_path_buf(), error },
+    })?;
+    let mut assembler = Assembler::new();
+    let dir = path.parent().unwrap_or(Path::new(""));
+    assembler.parse(&source, Some(path), dir, 0)?;
+    assembler.emit()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Comma,
+    Colon,
+    Equals,
+    Plus,
+    Minus,
+    LBracket,
+    RBracket,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Keyword {
+    I,
+    DT,
+    ST,
+    K,
+    F,
+    HF,
+    B,
+    R,
+}
+
+#[derive(Clone, Debug)]
+enum Term {
+    Number(i64),
+    Symbol(String),
+}
+
+#[derive(Clone, Debug)]
+struct Expr {
+    // Terms are summed, the flag negates a term
+    terms: Vec<(bool, Term, usize)>,
+    column: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Register(u8),
+    Keyword(Keyword),
+    IndirectIndex,
+    Long(Expr),
+    Expr(Expr),
+    Str(String),
+}
+
+enum Body {
+    Instruction(String, Vec<Operand>),
+    Bytes(Vec<Operand>),
+    Words(Vec<Operand>),
+    Sprite(Vec<String>),
+}
+
+struct Statement {
+    file: Option<PathBuf>,
+    line: usize,
+    column: usize,
+    address: u32,
+    body: Body,
+}
+
+struct Constant {
+    name: String,
+    expr: Expr,
+    file: Option<PathBuf>,
+    line: usize,
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    // Labels and constants, constants are `None` until resolved
+    symbols: HashMap<String, Option<i64>>,
+    constants: Vec<Constant>,
+    address: u32,
+}
+
+const MNEMONICS: [&str; 32] = [
+    "CLS", "RET", "SYS", "JP", "CALL", "SE", "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB",
+    "SHR", "SUBN", "SHL", "RND", "DRW", "SKP", "SKNP", "SCD", "SCR", "SCL", "EXIT", "LOW",
+    "HIGH", "SCU", "SAVE", "LOAD", "PLANE", "AUDIO", "PITCH",
+];
+
+fn tokenize(line: &str) -> Result<Vec<(Token, usize)>, (usize, ErrorKind)> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        let single = match c {
+            ',' => Some(Token::Comma),
+            ':' => Some(Token::Colon),
+            '=' => Some(Token::Equals),
+            '+' => Some(Token::Plus),
+            '-' => Some(Token::Minus),
+            '[' => Some(Token::LBracket),
+            ']' => Some(Token::RBracket),
+            _ => None,
+        };
+        if let Some(token) = single {
+            tokens.push((token, column));
+            i += 1;
+        } else if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '"' {
+            let end = chars[i + 1..].iter().position(|&c| c == '"')
+                .ok_or((column, ErrorKind::UnterminatedString))?;
+            tokens.push((Token::Str(chars[i + 1..i + 1 + end].iter().collect()), column));
+            i += end + 2;
+        } else if c.is_ascii_digit() || c.is_alphabetic() || c == '_' || c == '.' {
+            let len = chars[i..].iter()
+                .position(|&c| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(chars.len() - i);
+            let word: String = chars[i..i + len].iter().collect();
+            if c.is_ascii_digit() {
+                let number = parse_number(&word).ok_or((column, ErrorKind::InvalidNumber(word)))?;
+                tokens.push((Token::Number(number), column));
+            } else {
+                tokens.push((Token::Ident(word), column));
+            }
+            i += len;
+        } else {
+            return Err((column, ErrorKind::UnexpectedCharacter(c)));
+        }
+    }
+    Ok(tokens)
+}
+
+// Decimal, `0x` hexadecimal or `0b` binary, with optional `_` separators
+fn parse_number(word: &str) -> Option<i64> {
+    let digits = word.replace('_', "");
+    let lower = digits.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn register(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v' | 'V'), Some(digit), None) => digit.to_digit(16).map(|reg| reg as u8),
+        _ => None,
+    }
+}
+
+fn keyword(name: &str) -> Option<Keyword> {
+    match name.to_ascii_uppercase().as_str() {
+        "I" => Some(Keyword::I),
+        "DT" => Some(Keyword::DT),
+        "ST" => Some(Keyword::ST),
+        "K" => Some(Keyword::K),
+        "F" => Some(Keyword::F),
+        "HF" => Some(Keyword::HF),
+        "B" => Some(Keyword::B),
+        "R" => Some(Keyword::R),
+        _ => None,
+    }
+}
+
+fn is_reserved(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    register(name).is_some() || keyword(name).is_some() || MNEMONICS.contains(&upper.as_str())
+        || ["LONG", "DB", "DW", "SPRITE", "INCLUDE"].contains(&upper.as_str())
+}
+
+fn parse_expr(tokens: &[(Token, usize)], column: usize) -> Result<Expr, (usize, ErrorKind)> {
+    let mut terms = Vec::new();
+    let mut rest = tokens;
+    loop {
+        let mut negative = false;
+        if !terms.is_empty() {
+            match rest {
+                [] => break,
+                [(Token::Plus, _), after @ ..] => rest = after,
+                [(Token::Minus, _), after @ ..] => {
+                    negative = true;
+                    rest = after;
+                }
+                [(_, column), ..] => return Err((*column, ErrorKind::UnexpectedToken)),
+            }
+        }
+        if let [(Token::Minus, _), after @ ..] = rest {
+            negative = !negative;
+            rest = after;
+        }
+        match rest {
+            [(Token::Number(number), column), after @ ..] => {
+                terms.push((negative, Term::Number(*number), *column));
+                rest = after;
+            }
+            [(Token::Ident(name), column), after @ ..] => {
+                if is_reserved(name) {
+                    return Err((*column, ErrorKind::ReservedName(name.clone())));
+                }
+                terms.push((negative, Term::Symbol(name.clone()), *column));
+                rest = after;
+            }
+            [(_, column), ..] => return Err((*column, ErrorKind::ExpectedExpression)),
+            [] => return Err((column, ErrorKind::ExpectedExpression)),
+        }
+    }
+    Ok(Expr { terms, column })
+}
+
+fn parse_operand(tokens: &[(Token, usize)], column: usize) -> Result<Operand, (usize, ErrorKind)> {
+    match tokens {
+        [] => Err((column, ErrorKind::ExpectedOperand)),
+        [(Token::Ident(name), _)] if register(name).is_some() => {
+            Ok(Operand::Register(register(name).unwrap_or_default()))
+        }
+        [(Token::Ident(name), _)] if keyword(name).is_some() => {
+            Ok(Operand::Keyword(keyword(name).unwrap_or(Keyword::I)))
+        }
+        [(Token::LBracket, _), (Token::Ident(name), _), (Token::RBracket, _)] if keyword(name) == Some(Keyword::I) => {
+            Ok(Operand::IndirectIndex)
+        }
+        [(Token::Ident(name), _), rest @ ..] if name.eq_ignore_ascii_case("LONG") => {
+            let column = rest.first().map_or(column, |(_, column)| *column);
+            Ok(Operand::Long(parse_expr(rest, column)?))
+        }
+        [(Token::Str(text), _)] => Ok(Operand::Str(text.clone())),
+        [(Token::Str(_), _), (_, column), ..] => Err((*column, ErrorKind::UnexpectedToken)),
+        _ => Ok(Operand::Expr(parse_expr(tokens, column)?)),
+    }
+}
+
+// Splits the tokens after a mnemonic on commas
+fn parse_operands(tokens: &[(Token, usize)]) -> Result<Vec<Operand>, (usize, ErrorKind)> {
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut operands = Vec::new();
+    let mut start_column = tokens[0].1;
+    let mut group = Vec::new();
+    for (token, token_column) in tokens {
+        if *token == Token::Comma {
+            operands.push(parse_operand(&group, start_column)?);
+            group.clear();
+            start_column = *token_column + 1;
+        } else {
+            if group.is_empty() {
+                start_column = *token_column;
+            }
+            group.push((token.clone(), *token_column));
+        }
+    }
+    operands.push(parse_operand(&group, start_column)?);
+    Ok(operands)
+}
+
+// One byte per 8 pixels, `#`, `X`, `*` and `1` set a pixel, `.`, `_`, `0` and
+// spaces leave it clear
+fn sprite_row(row: &str) -> Result<Vec<u8>, ErrorKind> {
+    let pixels: Vec<char> = row.chars().collect();
+    if pixels.len() > 16 {
+        return Err(ErrorKind::SpriteTooWide(pixels.len()));
+    }
+    let mut bytes = vec![0; pixels.len().div_ceil(8).max(1)];
+    for (i, &pixel) in pixels.iter().enumerate() {
+        match pixel {
+            '#' | 'X' | 'x' | '*' | '1' => bytes[i / 8] |= 0x80 >> (i % 8),
+            '.' | '_' | '0' | ' ' => (),
+            _ => return Err(ErrorKind::InvalidSprite(pixel)),
+        }
+    }
+    Ok(bytes)
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Assembler {
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+            constants: Vec::new(),
+            address: PROGRAM_START as u32,
+        }
+    }
+
+    fn error(file: Option<&Path>, line: usize, column: usize, kind: ErrorKind) -> AssemblyError {
+        AssemblyError { file: file.map(Path::to_path_buf), line, column, kind }
+    }
+
+    // First pass: tokenizes the source, follows includes, lays out the
+    // statements and records where labels are
+    fn parse(&mut self, source: &str, file: Option<&Path>, dir: &Path, depth: usize) -> Result<(), AssemblyError> {
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let at = |(column, kind): (usize, ErrorKind)| Assembler::error(file, line, column, kind);
+            let tokens = tokenize(text).map_err(at)?;
+            let mut rest = &tokens[..];
+
+            if let [(Token::Ident(name), column), (Token::Colon, _), after @ ..] = rest {
+                self.define(name, *column, Some(self.address as i64)).map_err(at)?;
+                rest = after;
+            }
+
+            let (mnemonic, column, operands) = match rest {
+                [] => continue,
+                [(Token::Ident(name), column), (Token::Equals, equals), after @ ..] => {
+                    let expr = parse_expr(after, equals + 1).map_err(at)?;
+                    self.define(name, *column, None).map_err(at)?;
+                    self.constants.push(Constant {
+                        name: name.clone(),
+                        expr,
+                        file: file.map(Path::to_path_buf),
+                        line,
+                    });
+                    continue;
+                }
+                [(Token::Ident(name), column), after @ ..] => (name.to_ascii_uppercase(), *column, after),
+                [(_, column), ..] => return Err(at((*column, ErrorKind::UnexpectedToken))),
+            };
+            let operands = parse_operands(operands).map_err(at)?;
+
+            let body = match mnemonic.as_str() {
+                "INCLUDE" => {
+                    let path = match &operands[..] {
+                        [Operand::Str(path)] => dir.join(path),
+                        _ => return Err(at((column, ErrorKind::InvalidOperands(mnemonic)))),
+                    };
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(at((column, ErrorKind::IncludeTooDeep)));
+                    }
+                    let source = fs::read_to_string(&path).map_err(|error| {
+                        at((column, ErrorKind::Include { path: path.clone(), error }))
+                    })?;
+                    let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+                    self.parse(&source, Some(&path), &dir, depth + 1)?;
+                    continue;
+                }
+                "DB" => Body::Bytes(operands),
+                "DW" => Body::Words(operands),
+                "SPRITE" => {
+                    let mut rows = Vec::new();
+                    for operand in operands {
+                        match operand {
+                            Operand::Str(row) => rows.push(row),
+                            _ => return Err(at((column, ErrorKind::InvalidOperands(mnemonic)))),
+                        }
+                    }
+                    Body::Sprite(rows)
+                }
+                _ if MNEMONICS.contains(&mnemonic.as_str()) => Body::Instruction(mnemonic, operands),
+                _ => return Err(at((column, ErrorKind::UnknownMnemonic(mnemonic)))),
+            };
+
+            let size = match &body {
+                Body::Instruction(_, operands) => {
+                    if operands.iter().any(|operand| matches!(operand, Operand::Long(_))) { 4 } else { 2 }
+                }
+                Body::Bytes(operands) => operands.iter().map(|operand| match operand {
+                    Operand::Str(text) => text.len() as u32,
+                    _ => 1,
+                }).sum(),
+                Body::Words(operands) => 2 * operands.len() as u32,
+                Body::Sprite(rows) => {
+                    let mut size = 0;
+                    for row in rows {
+                        size += sprite_row(row).map_err(|kind| at((column, kind)))?.len() as u32;
+                    }
+                    size
+                }
+            };
+            if self.address + size > 0x10000 {
+                return Err(at((column, ErrorKind::ProgramTooLarge)));
+            }
+            self.statements.push(Statement {
+                file: file.map(Path::to_path_buf),
+                line,
+                column,
+                address: self.address,
+                body,
+            });
+            self.address += size;
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, column: usize, value: Option<i64>) -> Result<(), (usize, ErrorKind)> {
+        if is_reserved(name) {
+            return Err((column, ErrorKind::ReservedName(name.to_string())));
+        }
+        if self.symbols.contains_key(name) {
+            return Err((column, ErrorKind::DuplicateSymbol(name.to_string())));
+        }
+        self.symbols.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    // Constants can use any label but only the constants defined before them
+    fn resolve_constants(&mut self) -> Result<(), AssemblyError> {
+        for constant in &self.constants {
+            let value = self.evaluate(&constant.expr).map_err(|(column, kind)| {
+                Assembler::error(constant.file.as_deref(), constant.line, column, kind)
+            })?;
+            self.symbols.insert(constant.name.clone(), Some(value));
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self, expr: &Expr) -> Result<i64, (usize, ErrorKind)> {
+        let mut value: i64 = 0;
+        for (negative, term, column) in &expr.terms {
+            let term = match term {
+                Term::Number(number) => *number,
+                Term::Symbol(name) => match self.symbols.get(name) {
+                    Some(Some(value)) => *value,
+                    _ => return Err((*column, ErrorKind::UndefinedSymbol(name.clone()))),
+                },
+            };
+            value = if *negative { value.wrapping_sub(term) } else { value.wrapping_add(term) };
+        }
+        Ok(value)
+    }
+
+    fn value(&self, expr: &Expr, min: i64, max: i64) -> Result<i64, (usize, ErrorKind)> {
+        let value = self.evaluate(expr)?;
+        if value < min || value > max {
+            return Err((expr.column, ErrorKind::OutOfRange { value, min, max }));
+        }
+        Ok(value)
+    }
+
+    fn addr(&self, expr: &Expr) -> Result<u16, (usize, ErrorKind)> {
+        self.value(expr, 0, 0xFFF).map(|value| value as u16)
+    }
+
+    fn byte(&self, expr: &Expr) -> Result<u8, (usize, ErrorKind)> {
+        self.value(expr, -128, 0xFF).map(|value| value as u8)
+    }
+
+    fn nibble(&self, expr: &Expr) -> Result<u8, (usize, ErrorKind)> {
+        self.value(expr, 0, 0xF).map(|value| value as u8)
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[Operand], column: usize) -> Result<Instruction, (usize, ErrorKind)> {
+        use Operand::{Expr as E, IndirectIndex, Keyword as Kw, Long, Register as V};
+
+        let instruction = match (mnemonic, operands) {
+            ("CLS", []) => Instruction::ClearDisplay,
+            ("RET", []) => Instruction::Return,
+            ("SYS", [E(addr)]) => Instruction::Sys(self.addr(addr)?),
+            ("JP", [E(addr)]) => Instruction::Jump(self.addr(addr)?),
+            ("JP", [V(0), E(addr)]) => Instruction::JumpWithOffset(self.addr(addr)?),
+            ("CALL", [E(addr)]) => Instruction::Call(self.addr(addr)?),
+            ("SE", [V(x), V(y)]) => Instruction::SkipIfEqualsRegister(*x, *y),
+            ("SE", [V(x), E(kk)]) => Instruction::SkipIfEqualsByte(*x, self.byte(kk)?),
+            ("SNE", [V(x), V(y)]) => Instruction::SkipIfNotEqualsRegister(*x, *y),
+            ("SNE", [V(x), E(kk)]) => Instruction::SkipIfNotEqualsByte(*x, self.byte(kk)?),
+            ("LD", [V(x), V(y)]) => Instruction::Move(*x, *y),
+            ("LD", [V(x), E(kk)]) => Instruction::LoadByte(*x, self.byte(kk)?),
+            ("LD", [V(x), Kw(Keyword::DT)]) => Instruction::LoadDelayTimer(*x),
+            ("LD", [V(x), Kw(Keyword::K)]) => Instruction::WaitKeyPress(*x),
+            ("LD", [V(x), IndirectIndex]) => Instruction::LoadRegisters(*x),
+            ("LD", [V(x), Kw(Keyword::R)]) => Instruction::LoadFlags(*x),
+            ("LD", [Kw(Keyword::I), E(addr)]) => Instruction::LoadIndex(self.addr(addr)?),
+            ("LD", [Kw(Keyword::I), Long(addr)]) => {
+                Instruction::LoadLongIndex(self.value(addr, 0, 0xFFFF)? as u16)
+            }
+            ("LD", [Kw(Keyword::DT), V(x)]) => Instruction::StoreDelayTimer(*x),
+            ("LD", [Kw(Keyword::ST), V(x)]) => Instruction::StoreSoundTimer(*x),
+            ("LD", [Kw(Keyword::F), V(x)]) => Instruction::LoadSprite(*x),
+            ("LD", [Kw(Keyword::HF), V(x)]) => Instruction::LoadBigSprite(*x),
+            ("LD", [Kw(Keyword::B), V(x)]) => Instruction::StoreBCD(*x),
+            ("LD", [IndirectIndex, V(x)]) => Instruction::StoreRegisters(*x),
+            ("LD", [Kw(Keyword::R), V(x)]) => Instruction::StoreFlags(*x),
+            ("ADD", [V(x), V(y)]) => Instruction::Add(*x, *y),
+            ("ADD", [V(x), E(kk)]) => Instruction::AddByte(*x, self.byte(kk)?),
+            ("ADD", [Kw(Keyword::I), V(x)]) => Instruction::AddToIndex(*x),
+            ("OR", [V(x), V(y)]) => Instruction::Or(*x, *y),
+            ("AND", [V(x), V(y)]) => Instruction::And(*x, *y),
+            ("XOR", [V(x), V(y)]) => Instruction::Xor(*x, *y),
+            ("SUB", [V(x), V(y)]) => Instruction::Subtract(*x, *y),
+            ("SUBN", [V(x), V(y)]) => Instruction::SubtractReverse(*x, *y),
+            // Without Vy the shift reads Vx whichever way the quirk goes
+            ("SHR", [V(x)]) => Instruction::ShiftRight(*x, *x),
+            ("SHR", [V(x), V(y)]) => Instruction::ShiftRight(*x, *y),
+            ("SHL", [V(x)]) => Instruction::ShifLeft(*x, *x),
+            ("SHL", [V(x), V(y)]) => Instruction::ShifLeft(*x, *y),
+            ("RND", [V(x), E(kk)]) => Instruction::RandomWithMask(*x, self.byte(kk)?),
+            ("DRW", [V(x), V(y), E(n)]) => Instruction::Draw(*x, *y, self.nibble(n)?),
+            ("SKP", [V(x)]) => Instruction::SkipIfPressed(*x),
+            ("SKNP", [V(x)]) => Instruction::SkipIfNotPressed(*x),
+            ("SCD", [E(n)]) => Instruction::ScrollDown(self.nibble(n)?),
+            ("SCR", []) => Instruction::ScrollRight,
+            ("SCL", []) => Instruction::ScrollLeft,
+            ("EXIT", []) => Instruction::Exit,
+            ("LOW", []) => Instruction::LowRes,
+            ("HIGH", []) => Instruction::HighRes,
+            ("SCU", [E(n)]) => Instruction::ScrollUp(self.nibble(n)?),
+            ("SAVE", [V(x), V(y)]) => Instruction::SaveRange(*x, *y),
+            ("LOAD", [V(x), V(y)]) => Instruction::LoadRange(*x, *y),
+            ("PLANE", [E(n)]) => Instruction::SelectPlanes(self.nibble(n)?),
+            ("AUDIO", []) => Instruction::LoadAudio,
+            ("PITCH", [V(x)]) => Instruction::SetPitch(*x),
+            _ => return Err((column, ErrorKind::InvalidOperands(mnemonic.to_string()))),
+        };
+        Ok(instruction)
+    }
+
+    // Second pass: evaluates operands now that every label is known
+    fn emit(&mut self) -> Result<Vec<u8>, AssemblyError> {
+        self.resolve_constants()?;
+        let mut rom = Vec::new();
+        for statement in &self.statements {
+            let at = |(column, kind): (usize, ErrorKind)| {
+                Assembler::error(statement.file.as_deref(), statement.line, column, kind)
+            };
+            debug_assert_eq!(statement.address, PROGRAM_START as u32 + rom.len() as u32);
+            match &statement.body {
+                Body::Instruction(mnemonic, operands) => {
+                    let instruction = self.instruction(mnemonic, operands, statement.column).map_err(at)?;
+                    rom.extend(instruction.encode_bytes());
+                }
+                Body::Bytes(operands) => {
+                    for operand in operands {
+                        match operand {
+                            Operand::Expr(expr) => rom.push(self.byte(expr).map_err(at)?),
+                            Operand::Str(text) => rom.extend(text.bytes()),
+                            _ => return Err(at((statement.column, ErrorKind::InvalidOperands("DB".to_string())))),
+                        }
+                    }
+                }
+                Body::Words(operands) => {
+                    for operand in operands {
+                        match operand {
+                            Operand::Expr(expr) => {
+                                let word = self.value(expr, -0x8000, 0xFFFF).map_err(at)? as u16;
+                                rom.extend(word.to_be_bytes());
+                            }
+                            _ => return Err(at((statement.column, ErrorKind::InvalidOperands("DW".to_string())))),
+                        }
+                    }
+                }
+                Body::Sprite(rows) => {
+                    for row in rows {
+                        rom.extend(sprite_row(row).map_err(|kind| at((statement.column, kind)))?);
+                    }
+                }
+            }
+        }
+        Ok(rom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::{assemble, assemble_file, ErrorKind};
+    use crate::instruction::Instruction;
+
+    #[test]
+    fn test_assemble_disassembled_instructions() {
+        for opcode in 0..=u16::MAX {
+            if let Ok(instruction) = Instruction::new(opcode) {
+                let rom = assemble(&instruction.to_string()).expect("Error assembling instruction");
+                assert_eq!(rom, instruction.encode_bytes(), "{}", instruction);
+            }
+        }
+        let long = Instruction::LoadLongIndex(0xABCD);
+        assert_eq!(assemble(&long.to_string()).expect("Error assembling instruction"), long.encode_bytes());
+    }
+
+    #[test]
+    fn test_assemble_labels() {
+        let source = "
+            start:  ld v0, 0        ; counter
+            loop:   add v0, 1
+                    se v0, LIMIT
+                    jp loop
+                    call done
+            done:   jp start + 2
+            LIMIT = 10
+        ";
+        let rom = assemble(source).expect("Error assembling program");
+        assert_eq!(rom, [0x60, 0x00, 0x70, 0x01, 0x30, 0x0A, 0x12, 0x02, 0x22, 0x0A, 0x12, 0x02]);
+    }
+
+    #[test]
+    fn test_assemble_data() {
+        let source = "
+            ld i, smiley
+            smiley:
+            sprite \"..####..\", \".#....#.\"
+            db 0b1010_0101, 0xFF, -1, \"AB\"
+            dw 0x1234, smiley
+        ";
+        let rom = assemble(source).expect("Error assembling program");
+        assert_eq!(rom, [0xA2, 0x02, 0x3C, 0x42, 0xA5, 0xFF, 0xFF, 0x41, 0x42, 0x12, 0x34, 0x02, 0x02]);
+    }
+
+    #[test]
+    fn test_assemble_wide_sprite() {
+        let rom = assemble("sprite \"################\", \"#\"").expect("Error assembling program");
+        assert_eq!(rom, [0xFF, 0xFF, 0x80]);
+    }
+
+    #[test]
+    fn test_assemble_include() {
+        let dir = std::env::temp_dir().join(format!("chip8-asm-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Error creating directory");
+        fs::write(dir.join("main.asm"), "include \"sprites.asm\"\nld i, ball\n").expect("Error writing file");
+        fs::write(dir.join("sprites.asm"), "jp 0x202\nball: db 0x80\n").expect("Error writing file");
+        let rom = assemble_file(dir.join("main.asm"));
+        fs::remove_dir_all(&dir).expect("Error removing directory");
+        assert_eq!(rom.expect("Error assembling program"), [0x12, 0x02, 0x80, 0xA2, 0x02]);
+    }
+
+    #[test]
+    fn test_assemble_missing_include() {
+        let error = assemble("cls\n  include \"missing.asm\"").expect_err("Missing include assembled");
+        assert_eq!((error.line, error.column), (2, 3));
+        assert!(matches!(error.kind, ErrorKind::Include { .. }));
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = assemble("cls\n  jmp 0x200").expect_err("Unknown mnemonic assembled");
+        assert_eq!((error.line, error.column), (2, 3));
+        assert!(matches!(error.kind, ErrorKind::UnknownMnemonic(ref name) if name == "JMP"));
+        assert_eq!(error.to_string(), "line 2, column 3: unknown mnemonic 'JMP'");
+
+        let error = assemble("jp nowhere").expect_err("Undefined symbol assembled");
+        assert_eq!((error.line, error.column), (1, 4));
+        assert!(matches!(error.kind, ErrorKind::UndefinedSymbol(_)));
+
+        let error = assemble("ld v0, 256").expect_err("Byte out of range assembled");
+        assert_eq!((error.line, error.column), (1, 8));
+        assert!(matches!(error.kind, ErrorKind::OutOfRange { value: 256, .. }));
+
+        let error = assemble("a: cls\na: cls").expect_err("Duplicate label assembled");
+        assert_eq!((error.line, error.column), (2, 1));
+        assert!(matches!(error.kind, ErrorKind::DuplicateSymbol(_)));
+
+        let error = assemble("drw v0, v1").expect_err("Missing operand assembled");
+        assert!(matches!(error.kind, ErrorKind::InvalidOperands(_)));
+
+        let error = assemble("ld v0, 0x1G").expect_err("Invalid number assembled");
+        assert_eq!((error.line, error.column), (1, 8));
+
+        let error = assemble("ld v0,").expect_err("Empty operand assembled");
+        assert!(matches!(error.kind, ErrorKind::ExpectedOperand));
+
+        let error = assemble("sprite \"#.o\"").expect_err("Invalid sprite assembled");
+        assert!(matches!(error.kind, ErrorKind::InvalidSprite('o')));
+
+        let error = assemble("v1: cls").expect_err("Register as label assembled");
+        assert!(matches!(error.kind, ErrorKind::ReservedName(_)));
+    }
+
+    #[test]
+    fn test_assemble_constants_in_order() {
+        assert_eq!(assemble("ROWS = 2\nSIZE = ROWS + 1\nld v0, SIZE").expect("Error assembling program"), [0x60, 0x03]);
+        let error = assemble("SIZE = ROWS + 1\nROWS = 2").expect_err("Forward constant assembled");
+        assert_eq!((error.line, error.column), (1, 8));
+    }
+}
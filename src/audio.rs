@@ -0,0 +1,60 @@
+// XO-CHIP audio: a 1-bit, 128 sample pattern played back in a loop while the
+// sound timer is running
+pub const PATTERN_SIZE: usize = 16;
+pub const DEFAULT_PITCH: u8 = 64;
+
+pub struct Audio {
+    pattern: [u8; PATTERN_SIZE],
+    pitch: u8,
+}
+
+impl Audio {
+    pub fn new() -> Self {
+        Audio {
+            pattern: [0; PATTERN_SIZE],
+            pitch: DEFAULT_PITCH,
+        }
+    }
+
+    pub fn pattern(&self) -> &[u8; PATTERN_SIZE] {
+        &self.pattern
+    }
+
+    pub fn set_pattern(&mut self, pattern: [u8; PATTERN_SIZE]) {
+        self.pattern = pattern;
+    }
+
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.pitch = pitch;
+    }
+
+    // Samples per second the pattern is played at, 4000 Hz at the default pitch
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Audio::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Audio;
+
+    #[test]
+    fn test_playback_rate() {
+        let mut audio = Audio::new();
+        assert_eq!(audio.playback_rate(), 4000.0);
+        audio.set_pitch(112);
+        assert_eq!(audio.playback_rate(), 8000.0);
+        audio.set_pitch(16);
+        assert_eq!(audio.playback_rate(), 2000.0);
+    }
+}
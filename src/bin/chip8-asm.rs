@@ -0,0 +1,64 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use chip8_rust::assembler::assemble_file;
+
+const USAGE: &str = "usage: chip8-asm <source> [-o <rom>]";
+
+fn main() {
+    let mut source = None;
+    let mut output = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => match args.next() {
+                Some(path) => output = Some(PathBuf::from(path)),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if source.is_none() => source = Some(PathBuf::from(arg)),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+    let source = match source {
+        Some(source) => source,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    // Next to the source with the usual ROM extension unless given
+    let output = output.unwrap_or_else(|| source.with_extension("ch8"));
+    // A source already named .ch8 would be replaced by its own ROM
+    let same_file = match (fs::canonicalize(&source), fs::canonicalize(&output)) {
+        (Ok(source), Ok(output)) => source == output,
+        _ => source == output,
+    };
+    if same_file {
+        eprintln!("{}: output would overwrite the source, pick another with -o", output.display());
+        process::exit(2);
+    }
+
+    let rom = match assemble_file(&source) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+    if let Err(error) = fs::write(&output, &rom) {
+        eprintln!("{}: {}", output.display(), error);
+        process::exit(1);
+    }
+    println!("{}: {} bytes", output.display(), rom.len());
+}
@@ -0,0 +1,89 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process;
+use chip8_rust::debugger::{Command, Debugger};
+use chip8_rust::machine::Machine;
+use chip8_rust::variant::Variant;
+
+const USAGE: &str = "usage: chip8-debug <rom> [--variant chip8|schip|xochip]";
+
+fn main() {
+    let mut rom = None;
+    let mut variant = Variant::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--variant" => match args.next().map(|name| name.parse()) {
+                Some(Ok(parsed)) => variant = parsed,
+                Some(Err(error)) => {
+                    eprintln!("{}", error);
+                    process::exit(2);
+                }
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if rom.is_none() => rom = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+    let path = match rom {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let machine = match fs::read(&path).map_err(|error| error.to_string())
+        .and_then(|rom| Machine::for_variant(variant, &rom).map_err(|error| error.to_string()))
+    {
+        Ok(machine) => machine,
+        Err(error) => {
+            eprintln!("{}: {}", path, error);
+            process::exit(1);
+        }
+    };
+
+    let mut debugger = Debugger::new(machine);
+    println!("{}", debugger.current());
+    let stdin = io::stdin();
+    let mut last = None;
+    loop {
+        print!("(chip8) ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+        // An empty line repeats the previous command, like gdb
+        let command = if line.trim().is_empty() {
+            match last {
+                Some(command) => command,
+                None => continue,
+            }
+        } else {
+            match line.parse::<Command>() {
+                Ok(command) => command,
+                Err(error) => {
+                    println!("{}", error);
+                    continue;
+                }
+            }
+        };
+        if command == Command::Quit {
+            break;
+        }
+        println!("{}", debugger.execute(command));
+        last = Some(command);
+    }
+}
@@ -1,5 +1,52 @@
-use crate::ram::Ram;
-use crate::instruction::{Instruction, InstructionError};
+use std::error::Error;
+use std::fmt;
+use crate::audio::{Audio, PATTERN_SIZE};
+use crate::display::Display;
+use crate::font::{BIG_GLYPH_SIZE, GLYPH_SIZE};
+use crate::keypad::Keypad;
+use crate::quirks::Quirks;
+use crate::ram::{MemoryError, Ram, PROGRAM_START};
+use crate::rng::{RandomSource, SplitMix64};
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::variant::Variant;
+use crate::instruction::{Instruction, INSTRUCTION_SIZE, LONG_INSTRUCTION_PREFIX};
+
+#[derive(PartialEq, Debug)]
+pub enum CpuError {
+    StackOverflow,
+    StackUnderflow,
+    InvalidOpcode { opcode: u16, pc: u16 },
+    OutOfBounds { address: usize },
+    UnsupportedInstruction(Instruction),
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::StackOverflow => write!(f, "stack overflow"),
+            CpuError::StackUnderflow => write!(f, "stack underflow"),
+            CpuError::InvalidOpcode { opcode, pc } => {
+                write!(f, "invalid opcode {:04X} at {:04X}", opcode, pc)
+            }
+            CpuError::OutOfBounds { address } => {
+                write!(f, "memory access out of bounds at {:04X}", address)
+            }
+            CpuError::UnsupportedInstruction(instruction) => {
+                write!(f, "unsupported instruction {:?}", instruction)
+            }
+        }
+    }
+}
+
+impl Error for CpuError {}
+
+impl From<MemoryError> for CpuError {
+    fn from(err: MemoryError) -> Self {
+        match err {
+            MemoryError::OutOfBounds { address } => CpuError::OutOfBounds { address },
+        }
+    }
+}
 
 pub struct Cpu {
     registers: [u8; 16],
@@ -9,6 +56,23 @@ pub struct Cpu {
     stack: [u16; 16],
     pc: u16,
     sp: u8,
+    display: Display,
+    keypad: Keypad,
+    key_wait: Option<KeyWait>,
+    display_wait: bool,
+    halted: bool,
+    // SUPER-CHIP RPL user flags, kept across runs on the HP-48
+    flags: [u8; 16],
+    audio: Audio,
+    variant: Variant,
+    quirks: Quirks,
+    rng: Box<dyn RandomSource>,
+}
+
+// Fx0A in progress: the register to load and the key seen going down
+struct KeyWait {
+    register: u8,
+    pressed: Option<u8>,
 }
 
 impl Cpu {
@@ -18,24 +82,337 @@ impl Cpu {
             i: 0,
             delay_timer: 0,
             sound_timer: 0,
-            pc: 0,
+            pc: PROGRAM_START,
             sp: 0,
             stack: [0; 16],
+            display: Display::new(),
+            keypad: Keypad::new(),
+            key_wait: None,
+            display_wait: false,
+            halted: false,
+            flags: [0; 16],
+            audio: Audio::new(),
+            variant: Variant::default(),
+            quirks: Quirks::default(),
+            rng: Box::new(SplitMix64::default()),
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn set_i(&mut self, i: u16) {
+        self.i = i;
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.registers
+    }
+
+    pub fn set_register(&mut self, register: u8, value: u8) {
+        self.registers[register as usize & 0xF] = value;
+    }
+
+    // Return addresses currently on the stack, oldest first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn display_mut(&mut self) -> &mut Display {
+        &mut self.display
+    }
+
+    pub fn keypad(&self) -> &Keypad {
+        &self.keypad
+    }
+
+    pub fn keypad_mut(&mut self) -> &mut Keypad {
+        &mut self.keypad
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+
+    // The buzzer sounds for as long as the sound timer is non-zero
+    pub fn sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    // Called at 60 Hz to count both timers down towards zero
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    // Start of the vertical blank, which releases a Dxyn waiting on it
+    pub fn vblank(&mut self) {
+        self.display_wait = false;
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn set_rng(&mut self, rng: Box<dyn RandomSource>) {
+        self.rng = rng;
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.rng.seed()
+    }
+
+    pub(crate) fn take_rng(&mut self) -> Box<dyn RandomSource> {
+        std::mem::replace(&mut self.rng, Box::new(SplitMix64::default()))
+    }
+
+    pub(crate) fn write_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.registers);
+        state.u16(self.i);
+        state.u8(self.delay_timer);
+        state.u8(self.sound_timer);
+        for address in self.stack {
+            state.u16(address);
+        }
+        state.u16(self.pc);
+        state.u8(self.sp);
+        state.bool(self.key_wait.is_some());
+        if let Some(wait) = &self.key_wait {
+            state.u8(wait.register);
+            state.bool(wait.pressed.is_some());
+            state.u8(wait.pressed.unwrap_or(0));
+        }
+        state.bool(self.display_wait);
+        state.bool(self.halted);
+        state.bytes(&self.flags);
+        state.variant(self.variant);
+        state.quirks(&self.quirks);
+        self.display.write_state(state);
+        state.keypad(&self.keypad);
+        state.audio(&self.audio);
+        // Only sources that can be resumed are saved
+        match (self.rng.seed(), self.rng.state()) {
+            (Some(seed), Some(position)) => {
+                state.bool(true);
+                state.u64(seed);
+                state.u64(position);
+            }
+            _ => state.bool(false),
+        }
+    }
+
+    // Also returns whether the random source was restored, otherwise the
+    // CPU has a default one
+    pub(crate) fn read_state(state: &mut StateReader) -> Result<(Self, bool), StateError> {
+        let mut cpu = Cpu::new();
+        cpu.registers = state.array()?;
+        cpu.i = state.u16()?;
+        cpu.delay_timer = state.u8()?;
+        cpu.sound_timer = state.u8()?;
+        for address in cpu.stack.iter_mut() {
+            *address = state.u16()?;
+        }
+        cpu.pc = state.u16()?;
+        cpu.sp = state.u8()?;
+        if cpu.sp as usize > cpu.stack.len() {
+            return Err(StateError::Corrupt("invalid stack pointer"));
         }
+        if state.bool()? {
+            let register = state.u8()?;
+            if register > 0xF {
+                return Err(StateError::Corrupt("invalid register"));
+            }
+            let has_pressed = state.bool()?;
+            let pressed = state.u8()?;
+            if pressed > 0xF {
+                return Err(StateError::Corrupt("invalid key"));
+            }
+            cpu.key_wait = Some(KeyWait { register, pressed: has_pressed.then_some(pressed) });
+        }
+        cpu.display_wait = state.bool()?;
+        cpu.halted = state.bool()?;
+        cpu.flags = state.array()?;
+        cpu.variant = state.variant()?;
+        cpu.quirks = state.quirks()?;
+        cpu.display = Display::read_state(state)?;
+        cpu.keypad = state.keypad()?;
+        cpu.audio = state.audio()?;
+        let restored = state.bool()?;
+        if restored {
+            let seed = state.u64()?;
+            cpu.rng = Box::new(SplitMix64::from_state(seed, state.u64()?));
+        }
+        Ok((cpu, restored))
     }
 
-    fn fetch(&self, memory: Ram) -> u16 {
-        memory.word(self.pc)
+    pub fn variant(&self) -> Variant {
+        self.variant
     }
 
-    fn decode(instruction: u16) -> Result<Instruction, InstructionError> {
-        Instruction::new(instruction)
+    // Instructions outside the variant's set fail as unsupported
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
     }
 
-    fn execute(&mut self, instruction: Instruction) {
+    pub fn audio(&self) -> &Audio {
+        &self.audio
+    }
+
+    pub fn flags(&self) -> &[u8; 16] {
+        &self.flags
+    }
+
+    // Set once 00FD has exited the interpreter
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // Drawing waits for the next vertical blank when the quirk is on
+    pub fn is_waiting_display(&self) -> bool {
+        self.display_wait
+    }
+
+    pub fn is_waiting_key(&self) -> bool {
+        self.key_wait.is_some()
+    }
+
+    // One full fetch/decode/execute cycle
+    pub fn cycle(&mut self, memory: &mut Ram) -> Result<(), CpuError> {
+        if self.halted {
+            return Ok(());
+        }
+        if self.key_wait.is_some() {
+            self.poll_key_wait();
+            return Ok(());
+        }
+        if self.display_wait {
+            return Ok(());
+        }
+        let pc = self.pc;
+        let opcode = self.fetch(memory)?;
+        let instruction = Cpu::decode(memory, pc, opcode)?;
+        if !self.variant.supports(&instruction) {
+            return Err(CpuError::UnsupportedInstruction(instruction));
+        }
+        self.execute(instruction, memory)
+    }
+
+    // Reads the opcode at PC and moves PC past the whole instruction
+    fn fetch(&mut self, memory: &Ram) -> Result<u16, CpuError> {
+        let opcode = memory.fetch_word(self.pc)?;
+        self.pc = self.pc.wrapping_add(Instruction::size(opcode));
+        Ok(opcode)
+    }
+
+    // Skips the next instruction, which may be a long one
+    fn skip(&mut self, memory: &Ram) -> Result<(), CpuError> {
+        let opcode = memory.read_word(self.pc)?;
+        self.pc = self.pc.wrapping_add(Instruction::size(opcode));
+        Ok(())
+    }
+
+    // Access to the byte at I + offset
+    fn load(&self, memory: &Ram, offset: u16) -> Result<u8, CpuError> {
+        Ok(memory.read_u8(self.i.wrapping_add(offset))?)
+    }
+
+    fn store(&self, memory: &mut Ram, offset: u16, value: u8) -> Result<(), CpuError> {
+        Ok(memory.write_u8(self.i.wrapping_add(offset), value)?)
+    }
+
+    // Like the COSMAC VIP, Fx0A completes once a pressed key is released
+    fn poll_key_wait(&mut self) {
+        if let Some(wait) = &mut self.key_wait {
+            match wait.pressed {
+                None => wait.pressed = self.keypad.first_pressed(),
+                Some(key) if !self.keypad.is_pressed(key) => {
+                    self.registers[wait.register as usize] = key;
+                    self.key_wait = None;
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    // The original interpreter shifted Vy into Vx, later ones shift Vx in place
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.registers[y as usize]
+        } else {
+            self.registers[x as usize]
+        }
+    }
+
+    // Registers x to y inclusive, in descending order when y < x
+    fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = u8>> {
+        if x <= y {
+            Box::new(x..=y)
+        } else {
+            Box::new((y..=x).rev())
+        }
+    }
+
+    fn reset_flag_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.registers[0x0F] = 0;
+        }
+    }
+
+    fn decode(memory: &Ram, pc: u16, opcode: u16) -> Result<Instruction, CpuError> {
+        let decoded = if opcode == LONG_INSTRUCTION_PREFIX {
+            let operand = memory.read_word(pc.wrapping_add(INSTRUCTION_SIZE))?;
+            Instruction::new_long(opcode, operand)
+        } else {
+            Instruction::new(opcode)
+        };
+        decoded.map_err(|_| CpuError::InvalidOpcode { opcode, pc })
+    }
+
+    fn execute(&mut self, instruction: Instruction, memory: &mut Ram) -> Result<(), CpuError> {
         match instruction {
-            Instruction::ClearDisplay => {unimplemented!()}
+            Instruction::ClearDisplay => {
+                self.display.clear();
+            }
+            Instruction::Sys(_) => {
+                return Err(CpuError::UnsupportedInstruction(instruction));
+            }
             Instruction::Return => {
+                if self.sp == 0 {
+                    return Err(CpuError::StackUnderflow);
+                }
                 self.sp -= 1;
                 self.pc = self.stack[self.sp as usize];
             }
@@ -43,213 +420,435 @@ impl Cpu {
                 self.pc = addr;
             },
             Instruction::Call(addr) => {
+                if self.sp as usize == self.stack.len() {
+                    return Err(CpuError::StackOverflow);
+                }
                 self.stack[self.sp as usize] = self.pc;
                 self.sp += 1;
                 self.pc = addr;
             }
             Instruction::SkipIfEqualsByte(x, num) => {
-                self.pc += if self.registers[x as usize] == num {2} else {1};
+                if self.registers[x as usize] == num {
+                    self.skip(memory)?;
+                }
             }
             Instruction::SkipIfNotEqualsByte(x, num) => {
-                self.pc += if self.registers[x as usize] != num {2} else {1};
+                if self.registers[x as usize] != num {
+                    self.skip(memory)?;
+                }
             }
             Instruction::SkipIfEqualsRegister(x, y) => {
-                self.pc += if self.registers[x as usize] == self.registers[y as usize] {2} else {1};
+                if self.registers[x as usize] == self.registers[y as usize] {
+                    self.skip(memory)?;
+                }
             }
             Instruction::LoadByte(x, num) => {
                 self.registers[x as usize] = num;
-                self.pc += 1;
             }
             Instruction::AddByte(x, num) => {
-                self.registers[x as usize] += num;
-                self.pc += 1;
+                self.registers[x as usize] = self.registers[x as usize].wrapping_add(num);
             }
             Instruction::Move(x, y) => {
                 self.registers[x as usize] = self.registers[y as usize];
-                self.pc += 1;
             }
             Instruction::Or(x, y) => {
                 self.registers[x as usize] |= self.registers[y as usize];
-                self.pc += 1;
+                self.reset_flag_after_logic();
             }
             Instruction::And(x, y) => {
                 self.registers[x as usize] &= self.registers[y as usize];
-                self.pc += 1;
+                self.reset_flag_after_logic();
             }
             Instruction::Xor(x, y) => {
                 self.registers[x as usize] ^= self.registers[y as usize];
-                self.pc += 1;
+                self.reset_flag_after_logic();
             }
             Instruction::Add(x, y) => {
                 let (num, overflow) = self.registers[x as usize].overflowing_add(self.registers[y as usize]);
                 self.registers[x as usize] = num;
                 self.registers[0xF] = overflow as u8;
-                self.pc += 1;
             }
             Instruction::Subtract(x, y) => {
                 let (num, overflow) = self.registers[x as usize].overflowing_sub(self.registers[y as usize]);
                 self.registers[x as usize] = num;
                 self.registers[0x0F] = !overflow as u8;
-                self.pc += 1;
             }
-            Instruction::ShiftRight(x, _y) => {
-                self.registers[0x0F] = self.registers[x as usize] & 0x01;
-                self.registers[x as usize] >>= 1;
-                self.pc += 1;
+            Instruction::ShiftRight(x, y) => {
+                let num = self.shift_source(x, y);
+                self.registers[x as usize] = num >> 1;
+                self.registers[0x0F] = num & 0x01;
             }
             Instruction::SubtractReverse(x, y) => {
                 let (num, overflow) = self.registers[y as usize].overflowing_sub(self.registers[x as usize]);
                 self.registers[x as usize] = num;
                 self.registers[0x0F] = !overflow as u8;
-                self.pc += 1;
             }
-            Instruction::ShifLeft(x, _y) => {
-                self.registers[0x0F] = self.registers[x as usize] & 0x80;
-                self.registers[x as usize] <<= 1;
-                self.pc += 1;
+            Instruction::ShifLeft(x, y) => {
+                let num = self.shift_source(x, y);
+                self.registers[x as usize] = num << 1;
+                self.registers[0x0F] = num >> 7;
             }
             Instruction::SkipIfNotEqualsRegister(x, y) => {
-                self.pc += if self.registers[x as usize] != self.registers[y as usize] {2} else {1};
+                if self.registers[x as usize] != self.registers[y as usize] {
+                    self.skip(memory)?;
+                }
             }
             Instruction::LoadIndex(addr) => {
                 self.i = addr;
-                self.pc += 1;
             }
             Instruction::JumpWithOffset(addr) => {
-                self.pc = self.registers[0] as u16 + addr;
+                let reg = if self.quirks.jump_uses_vx { (addr >> 8) as usize } else { 0 };
+                self.pc = self.registers[reg] as u16 + addr;
+            }
+            Instruction::RandomWithMask(x, mask) => {
+                self.registers[x as usize] = self.rng.next_byte() & mask;
+            }
+            Instruction::Draw(x, y, n) => {
+                // Dxy0 draws a 16x16 sprite, 32 bytes long, except on the
+                // original interpreter where it draws nothing
+                let large = n == 0 && self.variant != Variant::Chip8;
+                let rows = if large { 32 } else { n as u16 };
+                let len = rows * self.display.planes().count_ones() as u16;
+                let sprite = (0..len)
+                    .map(|row| self.load(memory, row))
+                    .collect::<Result<Vec<u8>, CpuError>>()?;
+                let x = self.registers[x as usize] as usize;
+                let y = self.registers[y as usize] as usize;
+                let clip = self.quirks.clip_sprites;
+                let collision = if large {
+                    self.display.draw_large(x, y, &sprite, clip)
+                } else {
+                    self.display.draw(x, y, &sprite, clip)
+                };
+                self.registers[0x0F] = collision as u8;
+                self.display_wait = self.quirks.display_wait;
+            }
+            Instruction::SkipIfPressed(x) => {
+                if self.keypad.is_pressed(self.registers[x as usize] & 0x0F) {
+                    self.skip(memory)?;
+                }
+            }
+            Instruction::SkipIfNotPressed(x) => {
+                if !self.keypad.is_pressed(self.registers[x as usize] & 0x0F) {
+                    self.skip(memory)?;
+                }
             }
-            Instruction::RandomWithMask(x, mask) => {unimplemented!()}
-            Instruction::Draw(x, y, n) => {unimplemented!()}
-            Instruction::SkipIfPressed(x) => {unimplemented!()}
-            Instruction::SkipIfNotPressed(x) => {unimplemented!()}
             Instruction::LoadDelayTimer(x) => {
                 self.registers[x as usize] = self.delay_timer;
-                self.pc += 1;
             }
-            Instruction::WaitKeyPress(x) => {unimplemented!()}
+            Instruction::WaitKeyPress(x) => {
+                self.key_wait = Some(KeyWait { register: x, pressed: None });
+            }
             Instruction::StoreDelayTimer(x) => {
                 self.delay_timer = self.registers[x as usize];
-                self.pc += 1;
             }
             Instruction::StoreSoundTimer(x) => {
                 self.sound_timer = self.registers[x as usize];
-                self.pc += 1;
             }
             Instruction::AddToIndex(x) => {
-                self.i += self.registers[x as usize] as u16;
-                self.pc += 1;
+                self.i = self.i.wrapping_add(self.registers[x as usize] as u16);
+                if self.quirks.index_overflow_sets_vf {
+                    self.registers[0x0F] = (self.i > 0x0FFF) as u8;
+                }
+            }
+            Instruction::LoadSprite(x) => {
+                let digit = (self.registers[x as usize] & 0x0F) as u16;
+                self.i = memory.font_address() + digit * GLYPH_SIZE;
+            }
+            Instruction::StoreBCD(x) => {
+                let num = self.registers[x as usize];
+                self.store(memory, 0, num / 100)?;
+                self.store(memory, 1, num / 10 % 10)?;
+                self.store(memory, 2, num % 10)?;
+            }
+            Instruction::StoreRegisters(x) => {
+                for reg in 0..=x as u16 {
+                    self.store(memory, reg, self.registers[reg as usize])?;
+                }
+                self.i = self.i.wrapping_add(self.quirks.increment_index.amount(x));
+            }
+            Instruction::LoadRegisters(x) => {
+                for reg in 0..=x as u16 {
+                    self.registers[reg as usize] = self.load(memory, reg)?;
+                }
+                self.i = self.i.wrapping_add(self.quirks.increment_index.amount(x));
+            }
+            Instruction::ScrollDown(n) => {
+                self.display.scroll_down(n as usize);
+            }
+            Instruction::ScrollRight => {
+                self.display.scroll_right(4);
+            }
+            Instruction::ScrollLeft => {
+                self.display.scroll_left(4);
+            }
+            Instruction::Exit => {
+                self.halted = true;
+            }
+            Instruction::LowRes => {
+                self.display.set_hires(false);
+            }
+            Instruction::HighRes => {
+                self.display.set_hires(true);
+            }
+            Instruction::LoadBigSprite(x) => {
+                let digit = (self.registers[x as usize] & 0x0F) as u16;
+                self.i = memory.big_font_address() + digit * BIG_GLYPH_SIZE;
+            }
+            Instruction::StoreFlags(x) => {
+                let count = x as usize + 1;
+                self.flags[..count].copy_from_slice(&self.registers[..count]);
+            }
+            Instruction::LoadFlags(x) => {
+                let count = x as usize + 1;
+                self.registers[..count].copy_from_slice(&self.flags[..count]);
+            }
+            Instruction::ScrollUp(n) => {
+                self.display.scroll_up(n as usize);
+            }
+            Instruction::SaveRange(x, y) => {
+                for (offset, reg) in Cpu::register_range(x, y).enumerate() {
+                    self.store(memory, offset as u16, self.registers[reg as usize])?;
+                }
+            }
+            Instruction::LoadRange(x, y) => {
+                for (offset, reg) in Cpu::register_range(x, y).enumerate() {
+                    self.registers[reg as usize] = self.load(memory, offset as u16)?;
+                }
+            }
+            Instruction::LoadLongIndex(addr) => {
+                self.i = addr;
+            }
+            Instruction::SelectPlanes(n) => {
+                self.display.select_planes(n);
+            }
+            Instruction::LoadAudio => {
+                let mut pattern = [0; PATTERN_SIZE];
+                for (offset, byte) in pattern.iter_mut().enumerate() {
+                    *byte = self.load(memory, offset as u16)?;
+                }
+                self.audio.set_pattern(pattern);
+            }
+            Instruction::SetPitch(x) => {
+                self.audio.set_pitch(self.registers[x as usize]);
             }
-            Instruction::LoadSprite(x) => {unimplemented!()}
-            Instruction::StoreBCD(x) => {unimplemented!()}
-            Instruction::StoreRegisters(x) => {unimplemented!()}
-            Instruction::LoadRegisters(x) => {unimplemented!()}
         }
+        Ok(())
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Cpu::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Cpu;
+    use super::{Cpu, CpuError};
     use super::Instruction;
+    use crate::font::FONT;
+    use crate::quirks::{IndexIncrement, Quirks};
+    use crate::ram::{AddressPolicy, Ram};
+    use crate::rng::{RandomSource, SplitMix64};
+    use crate::variant::Variant;
 
-    // #[test]
-    // fn test_execute_clear_display() {
-    //     todo!();
-    // }
+    #[test]
+    fn test_execute_clear_display() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.display.draw(0, 0, &[0xFF], false);
+        cpu.execute(Instruction::ClearDisplay, &mut ram).expect("Error executing instruction");
+        assert!(cpu.display.pixels().iter().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn test_fetch() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0x63, 0xA7, 0xF0, 0x00, 0x12, 0x34]).expect("Error loading ROM");
+        assert_eq!(cpu.fetch(&ram), Ok(0x63A7));
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.fetch(&ram), Ok(0xF000));
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn test_cycle_skip() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0x32, 0x26, 0x63, 0xA7, 0x64, 0x01]).expect("Error loading ROM");
+        cpu.registers[2] = 0x26;
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x204);
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x206);
+        assert_eq!(cpu.registers[3], 0);
+        assert_eq!(cpu.registers[4], 1);
+    }
 
     #[test]
     fn test_execute_return() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.sp = 1;
         cpu.stack[0] = 0x381;
         cpu.pc = 0x245;
-        cpu.execute(Instruction::Return);
+        cpu.execute(Instruction::Return, &mut ram).expect("Error executing instruction");
 
         assert_eq!(cpu.sp, 0);
         assert_eq!(cpu.pc, 0x381);
     }
 
+    #[test]
+    fn test_execute_return_stack_underflow() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        let result = cpu.execute(Instruction::Return, &mut ram);
+        assert_eq!(result, Err(CpuError::StackUnderflow));
+        assert_eq!(cpu.sp, 0);
+    }
+
     #[test]
     fn test_execute_jump() {
         let mut cpu = Cpu::new();
-        cpu.execute(Instruction::Jump(0x238));
+        let mut ram = Ram::empty();
+        cpu.execute(Instruction::Jump(0x238), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.pc, 0x238);
     }
 
     #[test]
     fn test_execute_call() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.pc = 0x247;
-        cpu.execute(Instruction::Call(0x821));
+        cpu.execute(Instruction::Call(0x821), &mut ram).expect("Error executing instruction");
 
         assert_eq!(cpu.sp, 1);
         assert_eq!(cpu.stack[0], 0x247);
         assert_eq!(cpu.pc, 0x821);
     }
 
+    #[test]
+    fn test_execute_call_stack_overflow() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        for _ in 0..16 {
+            cpu.execute(Instruction::Call(0x300), &mut ram).expect("Error executing instruction");
+        }
+        let result = cpu.execute(Instruction::Call(0x300), &mut ram);
+        assert_eq!(result, Err(CpuError::StackOverflow));
+        assert_eq!(cpu.sp, 16);
+    }
+
+    #[test]
+    fn test_execute_sys_unsupported() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        let result = cpu.execute(Instruction::Sys(0x123), &mut ram);
+        assert_eq!(result, Err(CpuError::UnsupportedInstruction(Instruction::Sys(0x123))));
+    }
+
+    #[test]
+    fn test_cycle_invalid_opcode() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0x00, 0xE0, 0x80, 0x0F]).expect("Error loading ROM");
+        cpu.pc = 0x202;
+        let result = cpu.cycle(&mut ram);
+        assert_eq!(result, Err(CpuError::InvalidOpcode { opcode: 0x800F, pc: 0x202 }));
+    }
+
+    #[test]
+    fn test_cycle_fetch_out_of_bounds() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.pc = 0xFFF;
+        let result = cpu.cycle(&mut ram);
+        assert_eq!(result, Err(CpuError::OutOfBounds { address: 0x1000 }));
+    }
+
     #[test]
     fn test_execute_skip_if_equals_byte_skipping() {
         let mut cpu = Cpu::new();
-        cpu.pc = 0x247;
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
         cpu.registers[2] = 38;
-        cpu.execute(Instruction::SkipIfEqualsByte(2, 38));
-        assert_eq!(cpu.pc, 0x249);
+        cpu.execute(Instruction::SkipIfEqualsByte(2, 38), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x24A);
     }
 
     #[test]
     fn test_execute_skip_if_equals_byte_not_skipping() {
         let mut cpu = Cpu::new();
-        cpu.pc = 0x247;
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
         cpu.registers[2] = 29;
-        cpu.execute(Instruction::SkipIfEqualsByte(2, 38));
+        cpu.execute(Instruction::SkipIfEqualsByte(2, 38), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.pc, 0x248);
     }
 
+    #[test]
+    fn test_execute_skip_if_equals_byte_skipping_long_instruction() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0x00, 0x00, 0xF0, 0x00, 0x12, 0x34]).expect("Error loading ROM");
+        cpu.pc = 0x202;
+        cpu.registers[2] = 38;
+        cpu.execute(Instruction::SkipIfEqualsByte(2, 38), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x206);
+    }
+
     #[test]
     fn test_execute_skip_if_not_equals_byte_skipping() {
         let mut cpu = Cpu::new();
-        cpu.pc = 0x247;
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
         cpu.registers[2] = 29;
-        cpu.execute(Instruction::SkipIfNotEqualsByte(2, 38));
-        assert_eq!(cpu.pc, 0x249);
+        cpu.execute(Instruction::SkipIfNotEqualsByte(2, 38), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x24A);
     }
 
 
     #[test]
     fn test_execute_skip_if_not_equals_byte_not_skipping() {
         let mut cpu = Cpu::new();
-        cpu.pc = 0x247;
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
         cpu.registers[2] = 38;
-        cpu.execute(Instruction::SkipIfNotEqualsByte(2, 38));
+        cpu.execute(Instruction::SkipIfNotEqualsByte(2, 38), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.pc, 0x248);
     }
 
     #[test]
     fn test_execute_skip_if_equals_register_skipping() {
         let mut cpu = Cpu::new();
-        cpu.pc = 0x247;
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
         cpu.registers[2] = 38;
         cpu.registers[3] = 38;
-        cpu.execute(Instruction::SkipIfEqualsRegister(2, 3));
-        assert_eq!(cpu.pc, 0x249);
+        cpu.execute(Instruction::SkipIfEqualsRegister(2, 3), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x24A);
     }
 
     #[test]
     fn test_execute_skip_if_equals_register_not_skipping() {
         let mut cpu = Cpu::new();
-        cpu.pc = 0x247;
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
         cpu.registers[2] = 29;
         cpu.registers[3] = 83;
-        cpu.execute(Instruction::SkipIfEqualsRegister(2, 3));
+        cpu.execute(Instruction::SkipIfEqualsRegister(2, 3), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.pc, 0x248);
     }
 
     #[test]
     fn test_execute_load_byte() {
         let mut cpu = Cpu::new();
-        cpu.pc = 0x247;
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
         cpu.registers[2] = 38;
-        cpu.execute(Instruction::LoadByte(2, 3));
+        cpu.execute(Instruction::LoadByte(2, 3), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.pc, 0x248);
         assert_eq!(cpu.registers[2], 3);
     }
@@ -257,53 +856,94 @@ mod tests {
     #[test]
     fn test_execute_add_byte() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[6] = 39;
-        cpu.execute(Instruction::AddByte(6, 3));
+        cpu.execute(Instruction::AddByte(6, 3), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[6], 39 + 3);
     }
 
+    #[test]
+    fn test_execute_add_byte_wraps() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.registers[6] = 250;
+        cpu.registers[0xF] = 7;
+        cpu.execute(Instruction::AddByte(6, 10), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.registers[6], 4);
+        assert_eq!(cpu.registers[0xF], 7);
+    }
+
     #[test]
     fn test_execute_move() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[6] = 39;
         cpu.registers[8] = 42;
-        cpu.execute(Instruction::Move(6, 8));
+        cpu.execute(Instruction::Move(6, 8), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[6], 42);
     }
 
     #[test]
     fn test_execute_or() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[2] = 39;
         cpu.registers[7] = 42;
-        cpu.execute(Instruction::Or(2, 7));
+        cpu.execute(Instruction::Or(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 39 | 42);
     }
 
     #[test]
     fn test_execute_and() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[2] = 39;
         cpu.registers[7] = 42;
-        cpu.execute(Instruction::And(2, 7));
+        cpu.execute(Instruction::And(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 39 & 42);
     }
 
     #[test]
     fn test_execute_xor() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[2] = 39;
         cpu.registers[7] = 42;
-        cpu.execute(Instruction::Xor(2, 7));
+        cpu.execute(Instruction::Xor(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 39 ^ 42);
     }
 
+    #[test]
+    fn test_execute_logic_resets_vf() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.logic_resets_vf = true;
+        for instruction in [Instruction::Or(2, 7), Instruction::And(2, 7), Instruction::Xor(2, 7)] {
+            cpu.registers[0xF] = 1;
+            cpu.execute(instruction, &mut ram).expect("Error executing instruction");
+            assert_eq!(cpu.registers[0xF], 0);
+        }
+    }
+
+    #[test]
+    fn test_execute_logic_keeps_vf() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.logic_resets_vf = false;
+        for instruction in [Instruction::Or(2, 7), Instruction::And(2, 7), Instruction::Xor(2, 7)] {
+            cpu.registers[0xF] = 1;
+            cpu.execute(instruction, &mut ram).expect("Error executing instruction");
+            assert_eq!(cpu.registers[0xF], 1);
+        }
+    }
+
     #[test]
     fn test_execute_add() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[2] = 39;
         cpu.registers[7] = 42;
-        cpu.execute(Instruction::Add(2, 7));
+        cpu.execute(Instruction::Add(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 39 + 42);
         assert_eq!(cpu.registers[0xF], 0);
     }
@@ -311,9 +951,10 @@ mod tests {
     #[test]
     fn test_execute_add_with_overflow() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[2] = 200;
         cpu.registers[7] = 100;
-        cpu.execute(Instruction::Add(2, 7));
+        cpu.execute(Instruction::Add(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 200u8.wrapping_add(100));
         assert_eq!(cpu.registers[0xF], 1);
     }
@@ -321,9 +962,10 @@ mod tests {
     #[test]
     fn test_execute_subtract() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[2] = 200;
         cpu.registers[7] = 100;
-        cpu.execute(Instruction::Subtract(2, 7));
+        cpu.execute(Instruction::Subtract(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 200 - 100);
         assert_eq!(cpu.registers[0xF], 1);
     }
@@ -331,9 +973,10 @@ mod tests {
     #[test]
     fn test_execute_subtract_with_borrow() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[2] = 100;
         cpu.registers[7] = 200;
-        cpu.execute(Instruction::Subtract(2, 7));
+        cpu.execute(Instruction::Subtract(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 100u8.wrapping_sub(200));
         assert_eq!(cpu.registers[0xF], 0);
     }
@@ -341,8 +984,10 @@ mod tests {
     #[test]
     fn test_execute_shift_right_with_carry() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.shift_uses_vy = false;
         cpu.registers[2] = 85;
-        cpu.execute(Instruction::ShiftRight(2, 7));
+        cpu.execute(Instruction::ShiftRight(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 85>>1);
         assert_eq!(cpu.registers[0xF], 85&1);
     }
@@ -350,18 +995,44 @@ mod tests {
     #[test]
     fn test_execute_shift_right_no_carry() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.shift_uses_vy = false;
         cpu.registers[2] = 84;
-        cpu.execute(Instruction::ShiftRight(2, 7));
+        cpu.execute(Instruction::ShiftRight(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 84>>1);
         assert_eq!(cpu.registers[0xF], 84&1);
     }
 
+    #[test]
+    fn test_execute_shift_right_uses_vy() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.shift_uses_vy = true;
+        cpu.registers[2] = 84;
+        cpu.registers[7] = 85;
+        cpu.execute(Instruction::ShiftRight(2, 7), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.registers[2], 85>>1);
+        assert_eq!(cpu.registers[7], 85);
+        assert_eq!(cpu.registers[0xF], 1);
+    }
+
+    #[test]
+    fn test_execute_shift_right_into_vf() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.shift_uses_vy = false;
+        cpu.registers[0xF] = 0x84;
+        cpu.execute(Instruction::ShiftRight(0xF, 0), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.registers[0xF], 0);
+    }
+
     #[test]
     fn test_execute_subtract_reverse_with_borrow() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[2] = 200;
         cpu.registers[7] = 100;
-        cpu.execute(Instruction::SubtractReverse(2, 7));
+        cpu.execute(Instruction::SubtractReverse(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 100u8.wrapping_sub(200));
         assert_eq!(cpu.registers[0xF], 0);
     }
@@ -369,9 +1040,10 @@ mod tests {
     #[test]
     fn test_execute_subtract_reverse() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[2] = 100;
         cpu.registers[7] = 200;
-        cpu.execute(Instruction::SubtractReverse(2, 7));
+        cpu.execute(Instruction::SubtractReverse(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 200 - 100);
         assert_eq!(cpu.registers[0xF], 1);
     }
@@ -379,109 +1051,278 @@ mod tests {
     #[test]
     fn test_execute_shif_left_with_carry() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.shift_uses_vy = false;
         cpu.registers[2] = 149;
-        cpu.execute(Instruction::ShifLeft(2, 7));
-        assert_eq!(cpu.registers[2], 149<<1);
-        assert_eq!(cpu.registers[0xF], 149&0x80);
+        cpu.execute(Instruction::ShifLeft(2, 7), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.registers[2], 149u8.wrapping_shl(1));
+        assert_eq!(cpu.registers[0xF], (149&0x80)>>7);
     }
 
     #[test]
     fn test_execute_shif_left_no_carry() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.shift_uses_vy = false;
         cpu.registers[2] = 21;
-        cpu.execute(Instruction::ShifLeft(2, 7));
+        cpu.execute(Instruction::ShifLeft(2, 7), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[2], 21<<1);
-        assert_eq!(cpu.registers[0xF], 21&0x80);
+        assert_eq!(cpu.registers[0xF], (21&0x80)>>7);
+    }
+
+    #[test]
+    fn test_execute_shif_left_uses_vy() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.shift_uses_vy = true;
+        cpu.registers[2] = 21;
+        cpu.registers[7] = 149;
+        cpu.execute(Instruction::ShifLeft(2, 7), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.registers[2], 149u8.wrapping_shl(1));
+        assert_eq!(cpu.registers[0xF], 1);
     }
 
     #[test]
     fn test_execute_skip_if_not_equals_register_skipping() {
         let mut cpu = Cpu::new();
-        cpu.pc = 0x247;
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
         cpu.registers[2] = 38;
         cpu.registers[3] = 39;
-        cpu.execute(Instruction::SkipIfNotEqualsRegister(2, 3));
-        assert_eq!(cpu.pc, 0x249);
+        cpu.execute(Instruction::SkipIfNotEqualsRegister(2, 3), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x24A);
     }
 
     #[test]
     fn test_execute_skip_if_not_equals_register_not_skipping() {
         let mut cpu = Cpu::new();
-        cpu.pc = 0x247;
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
         cpu.registers[2] = 42;
         cpu.registers[3] = 42;
-        cpu.execute(Instruction::SkipIfNotEqualsRegister(2, 3));
+        cpu.execute(Instruction::SkipIfNotEqualsRegister(2, 3), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.pc, 0x248);
     }
 
     #[test]
     fn test_execute_load_index() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.i = 0x292;
-        cpu.execute(Instruction::LoadIndex(0x182));
+        cpu.execute(Instruction::LoadIndex(0x182), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.i, 0x182);
     }
 
     #[test]
     fn test_execute_jump_with_offset() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.jump_uses_vx = false;
         cpu.pc = 0x492;
         cpu.registers[0] = 0x2;
-        cpu.execute(Instruction::JumpWithOffset(0x132));
+        cpu.execute(Instruction::JumpWithOffset(0x132), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.pc, 0x132 + 0x2);
     }
 
-    // #[test]
-    // fn test_execute_random_with_mask() {
-    //     todo!();
-    // }
+    #[test]
+    fn test_execute_jump_with_offset_uses_vx() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.jump_uses_vx = true;
+        cpu.registers[0] = 0x2;
+        cpu.registers[1] = 0x4;
+        cpu.execute(Instruction::JumpWithOffset(0x132), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x132 + 0x4);
+    }
 
-    // #[test]
-    // fn test_execute_draw() {
-    //     todo!();
-    // }
+    struct FixedSource(u8);
 
-    // #[test]
-    // fn test_execute_skip_if_pressed() {
-    //     todo!();
-    // }
+    impl RandomSource for FixedSource {
+        fn next_byte(&mut self) -> u8 {
+            self.0
+        }
+    }
 
-    // #[test]
-    // fn test_execute_skip_if_not_pressed() {
-    //     todo!();
-    // }
+    #[test]
+    fn test_execute_random_with_mask() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.set_rng(Box::new(FixedSource(0xB6)));
+        cpu.execute(Instruction::RandomWithMask(3, 0x0F), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.registers[3], 0x06);
+        assert_eq!(cpu.seed(), None);
+    }
+
+    #[test]
+    fn test_execute_random_with_mask_seeded() {
+        let mut a = Cpu::new();
+        let mut b = Cpu::new();
+        let mut ram = Ram::empty();
+        a.set_rng(Box::new(SplitMix64::new(99)));
+        b.set_rng(Box::new(SplitMix64::new(99)));
+        for _ in 0..16 {
+            a.execute(Instruction::RandomWithMask(3, 0xFF), &mut ram).expect("Error executing instruction");
+            b.execute(Instruction::RandomWithMask(3, 0xFF), &mut ram).expect("Error executing instruction");
+            assert_eq!(a.registers[3], b.registers[3]);
+        }
+        assert_eq!(a.seed(), Some(99));
+    }
+
+    #[test]
+    fn test_execute_draw() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0x90, 0x60]).expect("Error loading ROM");
+        cpu.i = 0x200;
+        cpu.registers[1] = 10;
+        cpu.registers[2] = 5;
+        cpu.execute(Instruction::Draw(1, 2, 2), &mut ram).expect("Error executing instruction");
+        assert!(cpu.display.pixel(10, 5));
+        assert!(cpu.display.pixel(13, 5));
+        assert!(cpu.display.pixel(11, 6));
+        assert!(cpu.display.pixel(12, 6));
+        assert!(!cpu.display.pixel(11, 5));
+        assert_eq!(cpu.registers[0xF], 0);
+    }
+
+    #[test]
+    fn test_execute_draw_display_wait() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0xD0, 0x01, 0x60, 0x05]).expect("Error loading ROM");
+        cpu.quirks.display_wait = true;
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert!(cpu.is_waiting_display());
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x202);
+        cpu.vblank();
+        assert!(!cpu.is_waiting_display());
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x204);
+        assert_eq!(cpu.registers[0], 5);
+    }
+
+    #[test]
+    fn test_execute_draw_collision() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0x90, 0x60]).expect("Error loading ROM");
+        cpu.i = 0x200;
+        cpu.execute(Instruction::Draw(1, 2, 2), &mut ram).expect("Error executing instruction");
+        cpu.execute(Instruction::Draw(1, 2, 2), &mut ram).expect("Error executing instruction");
+        assert!(cpu.display.pixels().iter().all(|&p| p == 0));
+        assert_eq!(cpu.registers[0xF], 1);
+    }
+
+    #[test]
+    fn test_execute_skip_if_pressed() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
+        cpu.registers[3] = 0xA;
+        cpu.keypad.press(0xA);
+        cpu.execute(Instruction::SkipIfPressed(3), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x24A);
+    }
+
+    #[test]
+    fn test_execute_skip_if_pressed_not_skipping() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
+        cpu.registers[3] = 0xA;
+        cpu.keypad.press(0xB);
+        cpu.execute(Instruction::SkipIfPressed(3), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x248);
+    }
+
+    #[test]
+    fn test_execute_skip_if_not_pressed() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
+        cpu.registers[3] = 0xA;
+        cpu.execute(Instruction::SkipIfNotPressed(3), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x24A);
+    }
+
+    #[test]
+    fn test_execute_skip_if_not_pressed_not_skipping() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.pc = 0x248;
+        cpu.registers[3] = 0xA;
+        cpu.keypad.press(0xA);
+        cpu.execute(Instruction::SkipIfNotPressed(3), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x248);
+    }
 
     #[test]
     fn test_execute_load_delay_timer() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[0xA] = 0x2;
         cpu.delay_timer = 0x5;
-        cpu.execute(Instruction::LoadDelayTimer(0xA));
+        cpu.execute(Instruction::LoadDelayTimer(0xA), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[0xA], 0x5);
         assert_eq!(cpu.delay_timer, 0x5);
     }
 
-    // #[test]
-    // fn test_execute_wait_key_press() {
-    //     todo!();
-    // }
+    #[test]
+    fn test_execute_wait_key_press() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0xF5, 0x0A]).expect("Error loading ROM");
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert!(cpu.is_waiting_key());
+
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        cpu.keypad.press(0x7);
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert!(cpu.is_waiting_key());
+        assert_eq!(cpu.pc, 0x202);
+
+        cpu.keypad.release(0x7);
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert!(!cpu.is_waiting_key());
+        assert_eq!(cpu.registers[5], 0x7);
+        assert_eq!(cpu.pc, 0x202);
+    }
 
     #[test]
     fn test_execute_store_delay_timer() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[0xA] = 0x2;
         cpu.delay_timer = 0x5;
-        cpu.execute(Instruction::StoreDelayTimer(0xA));
+        cpu.execute(Instruction::StoreDelayTimer(0xA), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[0xA], 0x2);
         assert_eq!(cpu.delay_timer, 0x2);
     }
 
+    #[test]
+    fn test_tick_timers() {
+        let mut cpu = Cpu::new();
+        cpu.delay_timer = 2;
+        cpu.sound_timer = 1;
+        assert!(cpu.sound_active());
+        cpu.tick_timers();
+        assert_eq!(cpu.delay_timer, 1);
+        assert_eq!(cpu.sound_timer, 0);
+        assert!(!cpu.sound_active());
+        cpu.tick_timers();
+        cpu.tick_timers();
+        assert_eq!(cpu.delay_timer, 0);
+        assert_eq!(cpu.sound_timer, 0);
+    }
+
     #[test]
     fn test_execute_store_sound_timer() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.registers[0xA] = 0x2;
         cpu.sound_timer = 0x5;
-        cpu.execute(Instruction::StoreSoundTimer(0xA));
+        cpu.execute(Instruction::StoreSoundTimer(0xA), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.registers[0xA], 0x2);
         assert_eq!(cpu.sound_timer, 0x2);
     }
@@ -489,29 +1330,352 @@ mod tests {
     #[test]
     fn test_execute_add_to_index() {
         let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
         cpu.i = 0x4;
         cpu.registers[0xA] = 0x2;
-        cpu.execute(Instruction::AddToIndex(0xA));
+        cpu.execute(Instruction::AddToIndex(0xA), &mut ram).expect("Error executing instruction");
         assert_eq!(cpu.i, 0x4 + 0x2);
     }
 
-    // #[test]
-    // fn test_execute_load_sprite() {
-    //     todo!();
-    // }
+    #[test]
+    fn test_execute_add_to_index_overflow() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.index_overflow_sets_vf = true;
+        cpu.i = 0xFFE;
+        cpu.registers[0xA] = 0x2;
+        cpu.execute(Instruction::AddToIndex(0xA), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.i, 0x1000);
+        assert_eq!(cpu.registers[0xF], 1);
+    }
+
+    #[test]
+    fn test_execute_load_sprite() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.registers[4] = 0x3A;
+        cpu.execute(Instruction::LoadSprite(4), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.i, 0x050 + 0xA * 5);
+    }
+
+    #[test]
+    fn test_execute_load_sprite_custom_font_address() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::with_font(&FONT, 0x000);
+        cpu.registers[4] = 0x02;
+        cpu.execute(Instruction::LoadSprite(4), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.i, 0x00A);
+    }
+
+    #[test]
+    fn test_execute_store_bcd() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.i = 0x300;
+        cpu.registers[7] = 254;
+        cpu.execute(Instruction::StoreBCD(7), &mut ram).expect("Error executing instruction");
+        assert_eq!(ram.read_u8(0x300).unwrap(), 2);
+        assert_eq!(ram.read_u8(0x301).unwrap(), 5);
+        assert_eq!(ram.read_u8(0x302).unwrap(), 4);
+        assert_eq!(cpu.i, 0x300);
+    }
+
+    #[test]
+    fn test_execute_store_bcd_single_digit() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.i = 0x300;
+        cpu.registers[7] = 7;
+        cpu.execute(Instruction::StoreBCD(7), &mut ram).expect("Error executing instruction");
+        assert_eq!(ram.read_u8(0x300).unwrap(), 0);
+        assert_eq!(ram.read_u8(0x301).unwrap(), 0);
+        assert_eq!(ram.read_u8(0x302).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_execute_store_registers() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.increment_index = IndexIncrement::None;
+        cpu.i = 0x300;
+        cpu.registers[0] = 0x11;
+        cpu.registers[1] = 0x22;
+        cpu.registers[2] = 0x33;
+        cpu.registers[3] = 0x44;
+        cpu.execute(Instruction::StoreRegisters(2), &mut ram).expect("Error executing instruction");
+        assert_eq!(ram.read_u8(0x300).unwrap(), 0x11);
+        assert_eq!(ram.read_u8(0x301).unwrap(), 0x22);
+        assert_eq!(ram.read_u8(0x302).unwrap(), 0x33);
+        assert_eq!(ram.read_u8(0x303).unwrap(), 0x00);
+        assert_eq!(cpu.i, 0x300);
+    }
+
+    #[test]
+    fn test_execute_store_registers_increment_index() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.increment_index = IndexIncrement::ByXPlusOne;
+        cpu.i = 0x300;
+        cpu.execute(Instruction::StoreRegisters(2), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.i, 0x303);
+    }
+
+    #[test]
+    fn test_execute_store_registers_out_of_bounds() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.i = 0xFFE;
+        let result = cpu.execute(Instruction::StoreRegisters(3), &mut ram);
+        assert_eq!(result, Err(CpuError::OutOfBounds { address: 0x1000 }));
+    }
+
+    #[test]
+    fn test_execute_store_registers_wrapping() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.set_address_policy(AddressPolicy::Wrap);
+        cpu.quirks.increment_index = IndexIncrement::None;
+        cpu.i = 0xFFF;
+        cpu.registers[0] = 0x11;
+        cpu.registers[1] = 0x22;
+        cpu.execute(Instruction::StoreRegisters(1), &mut ram).expect("Error executing instruction");
+        assert_eq!(ram.read_u8(0xFFF).unwrap(), 0x11);
+        assert_eq!(ram.read_u8(0x000).unwrap(), 0x22);
+    }
+
+    #[test]
+    fn test_execute_load_registers() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.increment_index = IndexIncrement::None;
+        ram.load_rom(&[0x11, 0x22, 0x33, 0x44]).expect("Error loading ROM");
+        cpu.i = 0x200;
+        cpu.execute(Instruction::LoadRegisters(2), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.registers[0], 0x11);
+        assert_eq!(cpu.registers[1], 0x22);
+        assert_eq!(cpu.registers[2], 0x33);
+        assert_eq!(cpu.registers[3], 0x00);
+        assert_eq!(cpu.i, 0x200);
+    }
+
+    #[test]
+    fn test_execute_load_registers_increment_index() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks.increment_index = IndexIncrement::ByXPlusOne;
+        cpu.i = 0x200;
+        cpu.execute(Instruction::LoadRegisters(3), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.i, 0x204);
+    }
 
-    // #[test]
-    // fn test_execute_store_bcd() {
-    //     todo!();
-    // }
+    #[test]
+    fn test_execute_load_registers_increment_index_by_x() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.quirks = Quirks::chip48();
+        cpu.i = 0x200;
+        cpu.execute(Instruction::LoadRegisters(3), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.i, 0x203);
+    }
 
-    // #[test]
-    // fn test_execute_store_registers() {
-    //     todo!();
-    // }
+    #[test]
+    fn test_execute_scroll_down() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.display.draw(0, 0, &[0x80], true);
+        cpu.execute(Instruction::ScrollDown(3), &mut ram).expect("Error executing instruction");
+        assert!(cpu.display.pixel(0, 3));
+        assert!(!cpu.display.pixel(0, 0));
+    }
 
-    // #[test]
-    // fn test_execute_load_registers() {
-    //     todo!();
-    // }
+    #[test]
+    fn test_execute_scroll_right() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.display.draw(0, 0, &[0x80], true);
+        cpu.execute(Instruction::ScrollRight, &mut ram).expect("Error executing instruction");
+        assert!(cpu.display.pixel(4, 0));
+        assert!(!cpu.display.pixel(0, 0));
+    }
+
+    #[test]
+    fn test_execute_scroll_left() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.display.draw(4, 0, &[0x80], true);
+        cpu.execute(Instruction::ScrollLeft, &mut ram).expect("Error executing instruction");
+        assert!(cpu.display.pixel(0, 0));
+        assert!(!cpu.display.pixel(4, 0));
+    }
+
+    #[test]
+    fn test_execute_exit() {
+        let mut cpu = Cpu::new();
+        cpu.set_variant(Variant::SuperChip);
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0x00, 0xFD, 0x60, 0x01]).expect("Error loading ROM");
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert!(cpu.is_halted());
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.registers[0], 0);
+    }
+
+    #[test]
+    fn test_execute_high_and_low_res() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.execute(Instruction::HighRes, &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.display.width(), 128);
+        assert_eq!(cpu.display.height(), 64);
+        cpu.execute(Instruction::LowRes, &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.display.width(), 64);
+        assert_eq!(cpu.display.height(), 32);
+    }
+
+    #[test]
+    fn test_execute_draw_large() {
+        let mut cpu = Cpu::new();
+        cpu.set_variant(Variant::SuperChip);
+        let mut ram = Ram::empty();
+        let mut sprite = [0xFF; 32];
+        sprite[2] = 0x00;
+        ram.load_rom(&sprite).expect("Error loading ROM");
+        cpu.i = 0x200;
+        cpu.execute(Instruction::HighRes, &mut ram).expect("Error executing instruction");
+        cpu.execute(Instruction::Draw(0, 1, 0), &mut ram).expect("Error executing instruction");
+        assert!(cpu.display.pixel(0, 0));
+        assert!(cpu.display.pixel(15, 0));
+        assert!(!cpu.display.pixel(16, 0));
+        assert!(!cpu.display.pixel(0, 1));
+        assert!(cpu.display.pixel(8, 1));
+        assert!(cpu.display.pixel(15, 15));
+        assert_eq!(cpu.registers[0xF], 0);
+    }
+
+    #[test]
+    fn test_execute_load_big_sprite() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.registers[4] = 0x07;
+        cpu.execute(Instruction::LoadBigSprite(4), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.i, 0x0A0 + 7 * 10);
+    }
+
+    #[test]
+    fn test_execute_store_and_load_flags() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.registers[..4].copy_from_slice(&[1, 2, 3, 4]);
+        cpu.execute(Instruction::StoreFlags(2), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.flags[..4], [1, 2, 3, 0]);
+        cpu.registers = [0; 16];
+        cpu.execute(Instruction::LoadFlags(1), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.registers[..3], [1, 2, 0]);
+    }
+
+    #[test]
+    fn test_cycle_unsupported_by_variant() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0x00, 0xFF]).expect("Error loading ROM");
+        let result = cpu.cycle(&mut ram);
+        assert_eq!(result, Err(CpuError::UnsupportedInstruction(Instruction::HighRes)));
+        cpu.set_variant(Variant::SuperChip);
+        cpu.pc = 0x200;
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert!(cpu.display.is_hires());
+    }
+
+    #[test]
+    fn test_cycle_load_long_index() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::with_size(65536);
+        ram.load_rom(&[0xF0, 0x00, 0xBE, 0xEF, 0x60, 0x01]).expect("Error loading ROM");
+        cpu.set_variant(Variant::XoChip);
+        cpu.cycle(&mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.i, 0xBEEF);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn test_execute_draw_zero_rows_chip8() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0xFF; 32]).expect("Error loading ROM");
+        cpu.i = 0x200;
+        cpu.execute(Instruction::Draw(0, 0, 0), &mut ram).expect("Error executing instruction");
+        assert!(cpu.display.pixels().iter().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn test_execute_draw_both_planes() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[0x80, 0x40, 0xC0, 0xC0]).expect("Error loading ROM");
+        cpu.set_variant(Variant::XoChip);
+        cpu.i = 0x200;
+        cpu.execute(Instruction::SelectPlanes(3), &mut ram).expect("Error executing instruction");
+        cpu.execute(Instruction::Draw(0, 0, 2), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.display.pixel_value(0, 0), 0b11);
+        assert_eq!(cpu.display.pixel_value(1, 0), 0b10);
+        assert_eq!(cpu.display.pixel_value(1, 1), 0b11);
+        assert_eq!(cpu.display.pixel_value(0, 1), 0b10);
+    }
+
+    #[test]
+    fn test_execute_scroll_up() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.display.draw(0, 5, &[0x80], true);
+        cpu.execute(Instruction::ScrollUp(5), &mut ram).expect("Error executing instruction");
+        assert!(cpu.display.pixel(0, 0));
+        assert!(!cpu.display.pixel(0, 5));
+    }
+
+    #[test]
+    fn test_execute_save_range() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.i = 0x300;
+        cpu.registers[..5].copy_from_slice(&[1, 2, 3, 4, 5]);
+        cpu.execute(Instruction::SaveRange(1, 3), &mut ram).expect("Error executing instruction");
+        assert_eq!(ram.read_slice(0x300, 4), Ok(&[2, 3, 4, 0][..]));
+        cpu.execute(Instruction::SaveRange(4, 2), &mut ram).expect("Error executing instruction");
+        assert_eq!(ram.read_slice(0x300, 4), Ok(&[5, 4, 3, 0][..]));
+        assert_eq!(cpu.i, 0x300);
+    }
+
+    #[test]
+    fn test_execute_load_range() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        ram.load_rom(&[7, 8, 9]).expect("Error loading ROM");
+        cpu.i = 0x200;
+        cpu.execute(Instruction::LoadRange(2, 4), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.registers[2..5], [7, 8, 9]);
+        cpu.execute(Instruction::LoadRange(6, 5), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.registers[5..7], [8, 7]);
+        assert_eq!(cpu.i, 0x200);
+    }
+
+    #[test]
+    fn test_execute_load_audio() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        let pattern: Vec<u8> = (0..16).collect();
+        ram.load_rom(&pattern).expect("Error loading ROM");
+        cpu.i = 0x200;
+        cpu.execute(Instruction::LoadAudio, &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.audio.pattern()[..], pattern[..]);
+    }
+
+    #[test]
+    fn test_execute_set_pitch() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::empty();
+        cpu.registers[3] = 112;
+        cpu.execute(Instruction::SetPitch(3), &mut ram).expect("Error executing instruction");
+        assert_eq!(cpu.audio.pitch(), 112);
+    }
 }
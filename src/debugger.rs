@@ -0,0 +1,569 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use crate::cpu::CpuError;
+use crate::disassembler::{disassemble, disassemble_at};
+use crate::instruction::Instruction;
+use crate::keypad::KEY_COUNT;
+use crate::machine::{Machine, WatchEvent};
+use crate::ram::{Access, WatchAction, Watchpoint};
+use crate::rewind::Rewind;
+
+pub const DEFAULT_DUMP_SIZE: usize = 0x40;
+pub const DEFAULT_DISASSEMBLY_LINES: usize = 10;
+// Ten seconds at 60 Hz, so `continue` on a game's main loop comes back
+pub const DEFAULT_CONTINUE_FRAMES: u64 = 600;
+
+pub const HELP: &str = "\
+Addresses, lengths and values are hexadecimal, with or without 0x. Counts are
+decimal, or hexadecimal with 0x.
+  s, step [n]            take n steps, 1 by default
+  back [n]               undo n steps, 1 by default
+  rewind <frames>        go back a number of 60 Hz frames
+  c, continue            run until a breakpoint, exit, key wait, endless loop
+                         or for 600 frames
+  b, break <addr>        set a breakpoint on PC
+  delete <addr>          remove a breakpoint
+  breakpoints            list breakpoints
+  watch <r|w|x> <addr> [len]
+                         stop when memory is read, written or executed
+  trace <r|w|x> <addr> [len]
+                         log accesses to memory while running
+  unwatch <addr>         remove the watchpoints covering an address
+  r, regs                dump registers, timers and stack
+  m, mem [addr] [len]    hex dump memory, from I by default
+  d, dis [addr] [n]      disassemble n lines, around PC by default
+  set <reg> <value>      set V0-VF, I, PC, DT or ST
+  press <key>            press a key on the keypad
+  release <key>          release a key on the keypad
+  h, help                show this help
+  q, quit                leave the debugger";
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Target {
+    Register(u8),
+    Index,
+    ProgramCounter,
+    DelayTimer,
+    SoundTimer,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Command {
+    Step(usize),
+    Back(usize),
+    Rewind(u64),
+    Continue,
+    Break(u16),
+    Delete(u16),
+    Breakpoints,
+    Watch(Access, u16, usize, WatchAction),
+    Unwatch(u16),
+    Registers,
+    Memory(Option<u16>, usize),
+    Disassemble(Option<u16>, usize),
+    Set(Target, u16),
+    Press(u8),
+    Release(u8),
+    Help,
+    Quit,
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ParseCommandError(String);
+
+impl fmt::Display for ParseCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ParseCommandError {}
+
+fn number(text: &str) -> Result<u16, ParseCommandError> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| ParseCommandError(format!("invalid number '{}'", text)))
+}
+
+// Counts read like everyday numbers, hexadecimal only when asked for
+fn count(text: &str) -> Result<u64, ParseCommandError> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(digits) => u64::from_str_radix(digits, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| ParseCommandError(format!("invalid count '{}'", text)))
+}
+
+fn key(text: &str) -> Result<u8, ParseCommandError> {
+    match number(text)? {
+        key if (key as usize) < KEY_COUNT => Ok(key as u8),
+        _ => Err(ParseCommandError(format!("invalid key '{}'", text))),
+    }
+}
+
+fn access(text: &str) -> Result<Access, ParseCommandError> {
+    match text {
+        "r" => Ok(Access::Read),
+        "w" => Ok(Access::Write),
+        "x" => Ok(Access::Execute),
+        _ => Err(ParseCommandError(format!("unknown access '{}', expected r, w or x", text))),
+    }
+}
+
+fn target(text: &str) -> Result<Target, ParseCommandError> {
+    let lower = text.to_ascii_lowercase();
+    match lower.as_str() {
+        "i" => Ok(Target::Index),
+        "pc" => Ok(Target::ProgramCounter),
+        "dt" => Ok(Target::DelayTimer),
+        "st" => Ok(Target::SoundTimer),
+        _ => match lower.strip_prefix('v').map(|reg| u8::from_str_radix(reg, 16)) {
+            Some(Ok(reg)) if reg < 16 && lower.len() == 2 => Ok(Target::Register(reg)),
+            _ => Err(ParseCommandError(format!("unknown register '{}'", text))),
+        },
+    }
+}
+
+impl FromStr for Command {
+    type Err = ParseCommandError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words[..] {
+            ["s" | "step"] => Command::Step(1),
+            ["s" | "step", n] => Command::Step(count(n)? as usize),
+            ["back"] => Command::Back(1),
+            ["back", n] => Command::Back(count(n)? as usize),
+            ["rewind", frames] => Command::Rewind(count(frames)?),
+            ["c" | "continue"] => Command::Continue,
+            ["b" | "break", addr] => Command::Break(number(addr)?),
+            ["delete", addr] => Command::Delete(number(addr)?),
+            ["breakpoints"] => Command::Breakpoints,
+            ["watch" | "trace", kind, addr] | ["watch" | "trace", kind, addr, _] => {
+                let len = match words.get(3) {
+                    Some(len) => number(len)? as usize,
+                    None => 1,
+                };
+                let action = if words[0] == "watch" { WatchAction::Stop } else { WatchAction::Log };
+                Command::Watch(access(kind)?, number(addr)?, len, action)
+            }
+            ["unwatch", addr] => Command::Unwatch(number(addr)?),
+            ["r" | "regs"] => Command::Registers,
+            ["m" | "mem"] => Command::Memory(None, DEFAULT_DUMP_SIZE),
+            ["m" | "mem", addr] => Command::Memory(Some(number(addr)?), DEFAULT_DUMP_SIZE),
+            ["m" | "mem", addr, len] => Command::Memory(Some(number(addr)?), number(len)? as usize),
+            ["d" | "dis"] => Command::Disassemble(None, DEFAULT_DISASSEMBLY_LINES),
+            ["d" | "dis", addr] => Command::Disassemble(Some(number(addr)?), DEFAULT_DISASSEMBLY_LINES),
+            ["d" | "dis", addr, n] => Command::Disassemble(Some(number(addr)?), count(n)? as usize),
+            ["set", reg, value] => Command::Set(target(reg)?, number(value)?),
+            ["press", k] => Command::Press(key(k)?),
+            ["release", k] => Command::Release(key(k)?),
+            ["h" | "help"] => Command::Help,
+            ["q" | "quit"] => Command::Quit,
+            [] => return Err(ParseCommandError("empty command".to_string())),
+            [name, ..] => return Err(ParseCommandError(format!("unknown command or arguments for '{}', try help", name))),
+        };
+        Ok(command)
+    }
+}
+
+// Why `resume` gave control back
+#[derive(PartialEq, Debug)]
+pub enum Stop {
+    Breakpoint(u16),
+    Watchpoint(WatchEvent),
+    Halted,
+    WaitingKey,
+    // A jump to itself, which programs use to stop
+    EndlessLoop(u16),
+    // Ran out of frames without stopping on its own
+    StillRunning(u64),
+    Error(CpuError),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Breakpoint(addr) => write!(f, "breakpoint at {:04X}", addr),
+            Stop::Watchpoint(event) => write!(f, "watchpoint: {}", event),
+            Stop::Halted => write!(f, "program exited"),
+            Stop::WaitingKey => write!(f, "waiting for a key press"),
+            Stop::EndlessLoop(addr) => write!(f, "endless loop at {:04X}", addr),
+            Stop::StillRunning(frames) => write!(f, "still running after {} frames", frames),
+            Stop::Error(error) => write!(f, "error: {}", error),
+        }
+    }
+}
+
+pub struct Debugger {
+    machine: Machine,
+    breakpoints: BTreeSet<u16>,
+    history: Rewind,
+    continue_frames: u64,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine) -> Self {
+        Debugger::with_history(machine, Rewind::default())
+    }
+
+    pub fn with_history(machine: Machine, mut history: Rewind) -> Self {
+        history.snapshot(&machine);
+        Debugger { machine, breakpoints: BTreeSet::new(), history, continue_frames: DEFAULT_CONTINUE_FRAMES }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    // Changes made through this aren't seen by the history until the next
+    // snapshot, so stepping back over them replays without them
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    pub fn history(&self) -> &Rewind {
+        &self.history
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    // Most frames `resume` runs before giving control back
+    pub fn continue_frames(&self) -> u64 {
+        self.continue_frames
+    }
+
+    pub fn set_continue_frames(&mut self, frames: u64) {
+        assert!(frames > 0, "Continuing needs at least one frame");
+        self.continue_frames = frames;
+    }
+
+    // Always executes one instruction first so a breakpoint on the current PC
+    // doesn't stop straight away
+    pub fn resume(&mut self) -> Stop {
+        let start = self.machine.frames();
+        loop {
+            if let Err(error) = self.machine.step() {
+                self.machine.take_watch_stop();
+                return Stop::Error(error);
+            }
+            self.history.record(&self.machine);
+            if let Some(event) = self.machine.take_watch_stop() {
+                return Stop::Watchpoint(event);
+            }
+            let pc = self.machine.cpu().pc();
+            if self.machine.is_halted() {
+                return Stop::Halted;
+            }
+            if self.machine.cpu().is_waiting_key() {
+                return Stop::WaitingKey;
+            }
+            if self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint(pc);
+            }
+            let line = disassemble_at(self.machine.ram(), pc);
+            if line.and_then(|line| line.instruction) == Some(Instruction::Jump(pc)) {
+                return Stop::EndlessLoop(pc);
+            }
+            let frames = self.machine.frames() - start;
+            if frames >= self.continue_frames {
+                return Stop::StillRunning(frames);
+            }
+        }
+    }
+
+    // The instruction at PC, as shown after stepping
+    pub fn current(&self) -> String {
+        let pc = self.machine.cpu().pc();
+        match disassemble_at(self.machine.ram(), pc) {
+            Some(line) => format!("=> {}", line),
+            None => format!("=> {:04X}  out of memory", pc),
+        }
+    }
+
+    // Starts a little before the address, assuming the instructions there are
+    // aligned with it
+    pub fn disassemble_around(&self, address: u16, count: usize) -> String {
+        let start = (address as usize).saturating_sub(count / 2 * 2);
+        // Enough bytes for the lines even if they're all long instructions
+        let lines = disassemble(self.machine.ram(), start..start + (count + 1) * 4);
+        let pc = self.machine.cpu().pc();
+        let mut listing = String::new();
+        for line in lines.iter().take(count) {
+            let marker = match (line.address == pc, self.breakpoints.contains(&line.address)) {
+                (true, _) => "=>",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            listing.push_str(&format!("{} {}\n", marker, line));
+        }
+        listing
+    }
+
+    pub fn execute(&mut self, command: Command) -> String {
+        match command {
+            Command::Step(count) => {
+                let mut output = String::new();
+                for _ in 0..count {
+                    let result = self.machine.step();
+                    if let Err(error) = result {
+                        output.push_str(&format!("error: {}\n", error));
+                        break;
+                    }
+                    self.history.record(&self.machine);
+                    if let Some(event) = self.machine.take_watch_stop() {
+                        output.push_str(&format!("watchpoint: {}\n", event));
+                        break;
+                    }
+                }
+                format!("{}{}{}", self.events(), output, self.current())
+            }
+            Command::Back(count) => match self.history.step_back(&mut self.machine, count as u64) {
+                Ok(undone) if undone < count as u64 => {
+                    format!("history only goes back {} steps\n{}", undone, self.current())
+                }
+                Ok(_) => self.current(),
+                Err(error) => format!("error: {}\n{}", error, self.current()),
+            },
+            Command::Rewind(frames) => match self.history.rewind(&mut self.machine, frames) {
+                Ok(rewound) => format!("rewound {} frames\n{}", rewound, self.current()),
+                Err(error) => format!("error: {}\n{}", error, self.current()),
+            },
+            Command::Continue => {
+                let stop = self.resume();
+                format!("{}{}\n{}", self.events(), stop, self.current())
+            }
+            Command::Break(addr) => {
+                self.add_breakpoint(addr);
+                format!("breakpoint at {:04X}", addr)
+            }
+            Command::Delete(addr) => {
+                if self.remove_breakpoint(addr) {
+                    format!("deleted breakpoint at {:04X}", addr)
+                } else {
+                    format!("no breakpoint at {:04X}", addr)
+                }
+            }
+            Command::Breakpoints => {
+                if self.breakpoints.is_empty() {
+                    return "no breakpoints".to_string();
+                }
+                let addresses: Vec<String> = self.breakpoints.iter().map(|addr| format!("{:04X}", addr)).collect();
+                addresses.join(" ")
+            }
+            Command::Watch(access, addr, len, action) => {
+                let range = addr as usize..addr as usize + len.max(1);
+                let description = format!("{:?} watchpoint on {:04X}..{:04X}", access, range.start, range.end);
+                self.machine.ram_mut().add_watchpoint(Watchpoint { range, access, action });
+                description
+            }
+            Command::Unwatch(addr) => {
+                let count = self.machine.ram_mut().remove_watchpoints(addr);
+                format!("removed {} watchpoints", count)
+            }
+            Command::Registers => self.machine.to_string(),
+            Command::Memory(addr, len) => {
+                let start = addr.unwrap_or(self.machine.cpu().i()) as usize;
+                self.machine.ram().hex_dump(start..start + len).trim_end().to_string()
+            }
+            Command::Disassemble(addr, count) => {
+                let addr = addr.unwrap_or(self.machine.cpu().pc());
+                self.disassemble_around(addr, count).trim_end().to_string()
+            }
+            Command::Set(target, value) => self.set(target, value),
+            Command::Press(key) => {
+                self.machine.keypad_mut().press(key);
+                self.history.snapshot(&self.machine);
+                format!("pressed {:X}", key)
+            }
+            Command::Release(key) => {
+                self.machine.keypad_mut().release(key);
+                self.history.snapshot(&self.machine);
+                format!("released {:X}", key)
+            }
+            Command::Help => HELP.to_string(),
+            Command::Quit => String::new(),
+        }
+    }
+
+    // Logged memory accesses, one per line
+    fn events(&mut self) -> String {
+        self.machine.take_watch_events().iter().map(|event| format!("{}\n", event)).collect()
+    }
+
+    fn set(&mut self, target: Target, value: u16) -> String {
+        let cpu = self.machine.cpu_mut();
+        match target {
+            Target::Index => cpu.set_i(value),
+            Target::ProgramCounter => cpu.set_pc(value),
+            _ if value > 0xFF => return format!("value {:X} doesn't fit in a byte", value),
+            Target::Register(reg) => cpu.set_register(reg, value as u8),
+            Target::DelayTimer => cpu.set_delay_timer(value as u8),
+            Target::SoundTimer => cpu.set_sound_timer(value as u8),
+        }
+        self.history.snapshot(&self.machine);
+        self.machine.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Debugger, Stop, Target, DEFAULT_DUMP_SIZE};
+    use crate::ram::{Access, WatchAction};
+    use crate::machine::Machine;
+    use crate::ram::Ram;
+
+    // LD V0, 0x05; ADD V0, 0x01; SE V0, 0x08; JP 0x202; LD VF, K; JP 0x20A
+    const ROM: [u8; 12] = [0x60, 0x05, 0x70, 0x01, 0x30, 0x08, 0x12, 0x02, 0xFF, 0x0A, 0x12, 0x0A];
+
+    fn debugger() -> Debugger {
+        Debugger::new(Machine::new(Ram::from_bytes(&ROM).expect("Error loading ROM")))
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!("s".parse(), Ok(Command::Step(1)));
+        assert_eq!("step 10".parse(), Ok(Command::Step(10)));
+        assert_eq!("step 0x10".parse(), Ok(Command::Step(16)));
+        assert_eq!("dis 200 12".parse(), Ok(Command::Disassemble(Some(0x200), 12)));
+        assert!("step a".parse::<Command>().is_err());
+        assert_eq!("b 0x20A".parse(), Ok(Command::Break(0x20A)));
+        assert_eq!("mem 300".parse(), Ok(Command::Memory(Some(0x300), DEFAULT_DUMP_SIZE)));
+        assert_eq!("set vA ff".parse(), Ok(Command::Set(Target::Register(0xA), 0xFF)));
+        assert_eq!("set pc 200".parse(), Ok(Command::Set(Target::ProgramCounter, 0x200)));
+        assert!("set vg 1".parse::<Command>().is_err());
+        assert!("press 10".parse::<Command>().is_err());
+        assert!("jump".parse::<Command>().is_err());
+        assert!("".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = debugger();
+        let output = debugger.execute(Command::Step(2));
+        assert_eq!(debugger.machine().cpu().registers()[0], 0x06);
+        assert_eq!(output, "=> 0204  3008       SE V0, 0x08");
+    }
+
+    #[test]
+    fn test_continue_to_breakpoint() {
+        let mut debugger = debugger();
+        debugger.add_breakpoint(0x204);
+        assert_eq!(debugger.resume(), Stop::Breakpoint(0x204));
+        assert_eq!(debugger.machine().cpu().registers()[0], 0x06);
+        assert_eq!(debugger.resume(), Stop::Breakpoint(0x204));
+        assert_eq!(debugger.machine().cpu().registers()[0], 0x07);
+        assert!(debugger.remove_breakpoint(0x204));
+        assert_eq!(debugger.resume(), Stop::WaitingKey);
+        assert_eq!(debugger.machine().cpu().pc(), 0x20A);
+    }
+
+    #[test]
+    fn test_continue_to_endless_loop() {
+        let mut debugger = debugger();
+        debugger.resume();
+        debugger.execute(Command::Press(0x3));
+        debugger.execute(Command::Step(1));
+        debugger.execute(Command::Release(0x3));
+        assert_eq!(debugger.resume(), Stop::EndlessLoop(0x20A));
+        assert_eq!(debugger.machine().cpu().registers()[0xF], 0x3);
+    }
+
+    #[test]
+    fn test_continue_still_running() {
+        // ADD V0, 0x01; JP 0x200
+        let machine = Machine::new(Ram::from_bytes(&[0x70, 0x01, 0x12, 0x00]).expect("Error loading ROM"));
+        let mut debugger = Debugger::new(machine);
+        debugger.set_continue_frames(3);
+        assert_eq!(debugger.resume(), Stop::StillRunning(3));
+        assert_eq!(debugger.machine().frames(), 3);
+        assert!(debugger.execute(Command::Continue).starts_with("still running after 3 frames\n"));
+    }
+
+    #[test]
+    fn test_set() {
+        let mut debugger = debugger();
+        debugger.execute(Command::Set(Target::Register(0x3), 0x42));
+        debugger.execute(Command::Set(Target::Index, 0x300));
+        debugger.execute(Command::Set(Target::DelayTimer, 0x10));
+        debugger.execute(Command::Set(Target::ProgramCounter, 0x208));
+        let cpu = debugger.machine().cpu();
+        assert_eq!(cpu.registers()[0x3], 0x42);
+        assert_eq!(cpu.i(), 0x300);
+        assert_eq!(cpu.delay_timer(), 0x10);
+        assert_eq!(cpu.pc(), 0x208);
+        assert_eq!(debugger.execute(Command::Set(Target::Register(0), 0x100)), "value 100 doesn't fit in a byte");
+    }
+
+    #[test]
+    fn test_disassemble_around_pc() {
+        let mut debugger = debugger();
+        debugger.execute(Command::Step(2));
+        debugger.add_breakpoint(0x206);
+        let listing = debugger.execute(Command::Disassemble(None, 4));
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines, [
+            "   0200  6005       LD V0, 0x05",
+            "   0202  7001       ADD V0, 0x01",
+            "=> 0204  3008       SE V0, 0x08",
+            " * 0206  1202       JP 0x202",
+        ]);
+    }
+
+    #[test]
+    fn test_memory_dump() {
+        let mut debugger = debugger();
+        debugger.execute(Command::Set(Target::Index, 0x200));
+        let dump = debugger.execute(Command::Memory(None, 4));
+        assert_eq!(dump, "0200: 60 05 70 01                                      |`.p.|");
+    }
+
+    #[test]
+    fn test_watch_commands() {
+        assert_eq!("watch w 300 10".parse(), Ok(Command::Watch(Access::Write, 0x300, 0x10, WatchAction::Stop)));
+        assert_eq!("trace x 202".parse(), Ok(Command::Watch(Access::Execute, 0x202, 1, WatchAction::Log)));
+        assert!("watch q 300".parse::<Command>().is_err());
+
+        let mut debugger = debugger();
+        debugger.execute(Command::Watch(Access::Execute, 0x202, 1, WatchAction::Log));
+        debugger.execute(Command::Watch(Access::Execute, 0x208, 1, WatchAction::Stop));
+        let output = debugger.execute(Command::Continue);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "execute 7001 at 0202 by PC 0202 (ADD V0, 0x01)");
+        assert_eq!(lines[3], "watchpoint: execute FF0A at 0208 by PC 0208 (LD VF, K)");
+        assert!(matches!(debugger.resume(), Stop::WaitingKey));
+        assert_eq!(debugger.execute(Command::Unwatch(0x202)), "removed 1 watchpoints");
+    }
+
+    #[test]
+    fn test_back_and_rewind() {
+        assert_eq!("back".parse(), Ok(Command::Back(1)));
+        assert_eq!("back 10".parse(), Ok(Command::Back(10)));
+        assert_eq!("rewind 60".parse(), Ok(Command::Rewind(60)));
+
+        let mut debugger = debugger();
+        debugger.execute(Command::Step(3));
+        debugger.execute(Command::Set(Target::Register(0x3), 0x42));
+        debugger.execute(Command::Step(2));
+        assert_eq!(debugger.machine().cpu().registers()[0], 0x07);
+        assert_eq!(debugger.execute(Command::Back(2)), "=> 0206  1202       JP 0x202");
+        assert_eq!(debugger.machine().cpu().registers()[0], 0x06);
+        assert_eq!(debugger.machine().cpu().registers()[3], 0x42);
+        let output = debugger.execute(Command::Back(10));
+        assert_eq!(output, "history only goes back 3 steps\n=> 0200  6005       LD V0, 0x05");
+        assert_eq!(debugger.machine().cpu().registers()[3], 0x00);
+        assert_eq!(debugger.execute(Command::Rewind(1)), "rewound 0 frames\n=> 0200  6005       LD V0, 0x05");
+    }
+}
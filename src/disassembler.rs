@@ -0,0 +1,201 @@
+use std::fmt;
+use std::ops::Range;
+use crate::instruction::Instruction;
+use crate::ram::Ram;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Syntax {
+    // Mnemonics from Cowgod's technical reference, e.g. `SHL V4, V2`
+    #[default]
+    Cowgod,
+    // Octo assembly language, e.g. `v4 <<= v2`
+    Octo,
+}
+
+// One decoded instruction, or a data word that doesn't decode
+#[derive(PartialEq, Clone, Debug)]
+pub struct Line {
+    pub address: u16,
+    pub opcode: u16,
+    // Second word of XO-CHIP's `F000 NNNN`
+    pub operand: Option<u16>,
+    pub instruction: Option<Instruction>,
+}
+
+impl Line {
+    pub fn size(&self) -> u16 {
+        if self.operand.is_some() { 4 } else { 2 }
+    }
+
+    pub fn mnemonic(&self, syntax: Syntax) -> String {
+        match self.instruction {
+            Some(instruction) => mnemonic(&instruction, syntax),
+            None => match syntax {
+                Syntax::Cowgod => format!("DW 0x{:04X}", self.opcode),
+                Syntax::Octo => format!("0x{:02X} 0x{:02X}", self.opcode >> 8, self.opcode & 0xFF),
+            },
+        }
+    }
+
+    // Address, raw words and mnemonic, e.g. `0200  8426       SHR V4, V2`
+    pub fn format(&self, syntax: Syntax) -> String {
+        let raw = match self.operand {
+            Some(operand) => format!("{:04X} {:04X}", self.opcode, operand),
+            None => format!("{:04X}", self.opcode),
+        };
+        format!("{:04X}  {:<9}  {}", self.address, raw, self.mnemonic(syntax))
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format(Syntax::Cowgod))
+    }
+}
+
+pub fn mnemonic(instruction: &Instruction, syntax: Syntax) -> String {
+    match syntax {
+        Syntax::Cowgod => instruction.to_string(),
+        Syntax::Octo => octo(instruction),
+    }
+}
+
+fn octo(instruction: &Instruction) -> String {
+    match *instruction {
+        Instruction::Sys(addr) => format!("0x{:02X} 0x{:02X}", addr >> 8, addr & 0xFF),
+        Instruction::ClearDisplay => "clear".to_string(),
+        Instruction::Return => "return".to_string(),
+        Instruction::Jump(addr) => format!("jump 0x{:03X}", addr),
+        Instruction::Call(addr) => format!(":call 0x{:03X}", addr),
+        // Octo's conditionals name the case where the next instruction runs
+        Instruction::SkipIfEqualsByte(x, kk) => format!("if v{:x} != 0x{:02X} then", x, kk),
+        Instruction::SkipIfNotEqualsByte(x, kk) => format!("if v{:x} == 0x{:02X} then", x, kk),
+        Instruction::SkipIfEqualsRegister(x, y) => format!("if v{:x} != v{:x} then", x, y),
+        Instruction::LoadByte(x, kk) => format!("v{:x} := 0x{:02X}", x, kk),
+        Instruction::AddByte(x, kk) => format!("v{:x} += 0x{:02X}", x, kk),
+        Instruction::Move(x, y) => format!("v{:x} := v{:x}", x, y),
+        Instruction::Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+        Instruction::And(x, y) => format!("v{:x} &= v{:x}", x, y),
+        Instruction::Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+        Instruction::Add(x, y) => format!("v{:x} += v{:x}", x, y),
+        Instruction::Subtract(x, y) => format!("v{:x} -= v{:x}", x, y),
+        Instruction::ShiftRight(x, y) => format!("v{:x} >>= v{:x}", x, y),
+        Instruction::SubtractReverse(x, y) => format!("v{:x} =- v{:x}", x, y),
+        Instruction::ShifLeft(x, y) => format!("v{:x} <<= v{:x}", x, y),
+        Instruction::SkipIfNotEqualsRegister(x, y) => format!("if v{:x} == v{:x} then", x, y),
+        Instruction::LoadIndex(addr) => format!("i := 0x{:03X}", addr),
+        Instruction::JumpWithOffset(addr) => format!("jump0 0x{:03X}", addr),
+        Instruction::RandomWithMask(x, kk) => format!("v{:x} := random 0x{:02X}", x, kk),
+        Instruction::Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Instruction::SkipIfPressed(x) => format!("if v{:x} -key then", x),
+        Instruction::SkipIfNotPressed(x) => format!("if v{:x} key then", x),
+        Instruction::LoadDelayTimer(x) => format!("v{:x} := delay", x),
+        Instruction::WaitKeyPress(x) => format!("v{:x} := key", x),
+        Instruction::StoreDelayTimer(x) => format!("delay := v{:x}", x),
+        Instruction::StoreSoundTimer(x) => format!("buzzer := v{:x}", x),
+        Instruction::AddToIndex(x) => format!("i += v{:x}", x),
+        Instruction::LoadSprite(x) => format!("i := hex v{:x}", x),
+        Instruction::StoreBCD(x) => format!("bcd v{:x}", x),
+        Instruction::StoreRegisters(x) => format!("save v{:x}", x),
+        Instruction::LoadRegisters(x) => format!("load v{:x}", x),
+        Instruction::ScrollDown(n) => format!("scroll-down {}", n),
+        Instruction::ScrollRight => "scroll-right".to_string(),
+        Instruction::ScrollLeft => "scroll-left".to_string(),
+        Instruction::Exit => "exit".to_string(),
+        Instruction::LowRes => "lores".to_string(),
+        Instruction::HighRes => "hires".to_string(),
+        Instruction::LoadBigSprite(x) => format!("i := bighex v{:x}", x),
+        Instruction::StoreFlags(x) => format!("saveflags v{:x}", x),
+        Instruction::LoadFlags(x) => format!("loadflags v{:x}", x),
+        Instruction::ScrollUp(n) => format!("scroll-up {}", n),
+        Instruction::SaveRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+        Instruction::LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+        Instruction::LoadLongIndex(addr) => format!("i := long 0x{:04X}", addr),
+        Instruction::SelectPlanes(n) => format!("plane {}", n),
+        Instruction::LoadAudio => "audio".to_string(),
+        Instruction::SetPitch(x) => format!("pitch := v{:x}", x),
+    }
+}
+
+// Decodes one instruction, reading the operand of a long instruction too
+pub fn disassemble_at(ram: &Ram, address: u16) -> Option<Line> {
+    let opcode = ram.read_word(address).ok()?;
+    if Instruction::size(opcode) == 2 {
+        let instruction = Instruction::new(opcode).ok();
+        return Some(Line { address, opcode, operand: None, instruction });
+    }
+    match ram.read_word(address.wrapping_add(2)) {
+        Ok(operand) => {
+            let instruction = Instruction::new_long(opcode, operand).ok();
+            Some(Line { address, opcode, operand: Some(operand), instruction })
+        }
+        Err(_) => Some(Line { address, opcode, operand: None, instruction: None }),
+    }
+}
+
+// Walks memory linearly, so data mixed with code is shown as instructions
+// where it happens to decode
+pub fn disassemble(ram: &Ram, range: Range<usize>) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let end = range.end.min(ram.size());
+    let mut address = range.start;
+    while address < end {
+        let line = match disassemble_at(ram, address as u16) {
+            Some(line) => line,
+            None => break,
+        };
+        address += line.size() as usize;
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, mnemonic, Syntax};
+    use crate::instruction::Instruction;
+    use crate::ram::{Ram, XO_RAM_SIZE};
+
+    #[test]
+    fn test_disassemble() {
+        let ram = Ram::from_bytes(&[0x60, 0x01, 0x84, 0x2E, 0x80, 0x0F, 0x12, 0x00]).expect("Error loading ROM");
+        let lines = disassemble(&ram, 0x200..0x208);
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0].instruction, Some(Instruction::LoadByte(0, 0x01)));
+        assert_eq!(lines[2].instruction, None);
+        let listing: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        assert_eq!(listing, [
+            "0200  6001       LD V0, 0x01",
+            "0202  842E       SHL V4, V2",
+            "0204  800F       DW 0x800F",
+            "0206  1200       JP 0x200",
+        ]);
+    }
+
+    #[test]
+    fn test_disassemble_long_instruction() {
+        let mut ram = Ram::with_size(XO_RAM_SIZE);
+        ram.load_rom(&[0xF0, 0x00, 0xF0, 0x00, 0x00, 0xE0]).expect("Error loading ROM");
+        let lines = disassemble(&ram, 0x200..0x206);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].format(Syntax::Octo), "0200  F000 F000  i := long 0xF000");
+        assert_eq!(lines[1].address, 0x204);
+    }
+
+    #[test]
+    fn test_disassemble_stops_at_end_of_memory() {
+        let ram = Ram::empty();
+        let lines = disassemble(&ram, 0xFFC..0x2000);
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn test_octo_syntax() {
+        assert_eq!(mnemonic(&Instruction::ShifLeft(4, 2), Syntax::Octo), "v4 <<= v2");
+        assert_eq!(mnemonic(&Instruction::SkipIfEqualsByte(0xA, 0x10), Syntax::Octo), "if va != 0x10 then");
+        assert_eq!(mnemonic(&Instruction::SkipIfNotPressed(1), Syntax::Octo), "if v1 key then");
+        assert_eq!(mnemonic(&Instruction::Draw(0, 1, 5), Syntax::Octo), "sprite v0 v1 5");
+        assert_eq!(mnemonic(&Instruction::SaveRange(2, 5), Syntax::Octo), "save v2 - v5");
+        assert_eq!(mnemonic(&Instruction::ShifLeft(4, 2), Syntax::Cowgod), "SHL V4, V2");
+    }
+}
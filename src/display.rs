@@ -0,0 +1,408 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+// SUPER-CHIP high resolution mode
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+// XO-CHIP has two bitplanes, giving each pixel one of four colours
+pub const PLANE_COUNT: usize = 2;
+// 0xRRGGBB colour of each pixel value: off, plane 1, plane 2, both planes
+pub const DEFAULT_PALETTE: [u32; 4] = [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555];
+
+pub struct Display {
+    // One bit per plane for every pixel
+    pixels: Vec<u8>,
+    hires: bool,
+    planes: u8,
+    palette: [u32; 4],
+    dirty: bool,
+}
+
+impl Display {
+    pub fn new() -> Self {
+        Display {
+            pixels: vec![0; WIDTH * HEIGHT],
+            hires: false,
+            planes: 0b01,
+            palette: DEFAULT_PALETTE,
+            dirty: false,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_WIDTH } else { WIDTH }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires { HIRES_HEIGHT } else { HEIGHT }
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    // Switching resolution clears the screen
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.pixels = vec![0; self.width() * self.height()];
+        self.dirty = true;
+    }
+
+    // Bitmask of the planes drawing, clearing and scrolling apply to
+    pub fn planes(&self) -> u8 {
+        self.planes
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & 0b11;
+    }
+
+    pub fn palette(&self) -> &[u32; 4] {
+        &self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: [u32; 4]) {
+        self.palette = palette;
+        self.dirty = true;
+    }
+
+    // Whether the pixel is lit on any plane
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.pixel_value(x, y) != 0
+    }
+
+    // Plane bits of the pixel, an index into the palette
+    pub fn pixel_value(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width() + x]
+    }
+
+    pub fn color(&self, x: usize, y: usize) -> u32 {
+        self.palette[self.pixel_value(x, y) as usize]
+    }
+
+    // Row-major plane bits, width() by height()
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    // Set whenever the framebuffer changes, until a renderer marks it clean
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    // Clears the selected planes
+    pub fn clear(&mut self) {
+        let keep = !self.planes;
+        self.pixels.iter_mut().for_each(|p| *p &= keep);
+        self.dirty = true;
+    }
+
+    // XORs an 8 pixel wide sprite onto the screen. The starting position always
+    // wraps, the sprite itself is either clipped or wrapped at the edges.
+    // With several planes selected the sprite holds the rows of each plane in
+    // turn. Returns true if any lit pixel was turned off.
+    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
+        let rows: Vec<u16> = sprite.iter().map(|&byte| (byte as u16) << 8).collect();
+        self.draw_planes(x, y, &rows, 8, clip)
+    }
+
+    // SUPER-CHIP 16x16 sprite, two bytes per row
+    pub fn draw_large(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
+        let rows: Vec<u16> = sprite
+            .chunks(2)
+            .map(|row| (row[0] as u16) << 8 | *row.get(1).unwrap_or(&0) as u16)
+            .collect();
+        self.draw_planes(x, y, &rows, 16, clip)
+    }
+
+    fn draw_planes(&mut self, x: usize, y: usize, rows: &[u16], width: usize, clip: bool) -> bool {
+        let selected: Vec<u8> = (0..PLANE_COUNT as u8)
+            .map(|plane| 1 << plane)
+            .filter(|bit| self.planes & bit != 0)
+            .collect();
+        let mut collision = false;
+        if !selected.is_empty() {
+            let per_plane = rows.len() / selected.len();
+            for (bit, rows) in selected.iter().zip(rows.chunks(per_plane.max(1))) {
+                collision |= self.blit(x, y, rows, width, clip, *bit);
+            }
+        }
+        self.dirty = true;
+        collision
+    }
+
+    fn blit(&mut self, x: usize, y: usize, rows: &[u16], width: usize, clip: bool, plane: u8) -> bool {
+        let (w, h) = (self.width(), self.height());
+        let (x, y) = (x % w, y % h);
+        let mut collision = false;
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..width {
+                if bits & (0x8000 >> col) == 0 {
+                    continue;
+                }
+                if clip && (x + col >= w || y + row >= h) {
+                    continue;
+                }
+                let px = (x + col) % w;
+                let py = (y + row) % h;
+                let pixel = &mut self.pixels[py * w + px];
+                collision |= *pixel & plane != 0;
+                *pixel ^= plane;
+            }
+        }
+        collision
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.scroll(0, lines as isize);
+    }
+
+    // XO-CHIP
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.scroll(0, -(lines as isize));
+    }
+
+    pub fn scroll_right(&mut self, columns: usize) {
+        self.scroll(columns as isize, 0);
+    }
+
+    pub fn scroll_left(&mut self, columns: usize) {
+        self.scroll(-(columns as isize), 0);
+    }
+
+    // Moves the selected planes, filling the uncovered area with blank pixels
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (w, h) = (self.width() as isize, self.height() as isize);
+        let mask = self.planes;
+        let old = self.pixels.clone();
+        for y in 0..h {
+            for x in 0..w {
+                let (sx, sy) = (x - dx, y - dy);
+                let moved = if (0..w).contains(&sx) && (0..h).contains(&sy) {
+                    old[(sy * w + sx) as usize] & mask
+                } else {
+                    0
+                };
+                let pixel = &mut self.pixels[(y * w + x) as usize];
+                *pixel = (*pixel & !mask) | moved;
+            }
+        }
+        self.dirty = true;
+    }
+
+    pub(crate) fn write_state(&self, state: &mut StateWriter) {
+        state.bool(self.hires);
+        state.u8(self.planes);
+        for color in self.palette {
+            state.u32(color);
+        }
+        state.bytes(&self.pixels);
+    }
+
+    pub(crate) fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let hires = state.bool()?;
+        let planes = state.u8()?;
+        let mut palette = [0; 4];
+        for color in &mut palette {
+            *color = state.u32()?;
+        }
+        let size = if hires { HIRES_WIDTH * HIRES_HEIGHT } else { WIDTH * HEIGHT };
+        let pixels = state.bytes(size)?.to_vec();
+        if planes > 0b11 || pixels.iter().any(|&pixel| pixel > 0b11) {
+            return Err(StateError::Corrupt("invalid display planes"));
+        }
+        // Redraw whatever was on screen before
+        Ok(Display { pixels, hires, planes, palette, dirty: true })
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Display;
+
+    #[test]
+    fn test_draw() {
+        let mut display = Display::new();
+        let collision = display.draw(2, 3, &[0xC0, 0x01], false);
+        assert!(!collision);
+        assert!(display.pixel(2, 3));
+        assert!(display.pixel(3, 3));
+        assert!(!display.pixel(4, 3));
+        assert!(display.pixel(9, 4));
+        assert!(display.is_dirty());
+    }
+
+    #[test]
+    fn test_draw_collision() {
+        let mut display = Display::new();
+        display.draw(0, 0, &[0xF0], false);
+        let collision = display.draw(2, 0, &[0xF0], false);
+        assert!(collision);
+        assert!(display.pixel(0, 0));
+        assert!(!display.pixel(2, 0));
+        assert!(!display.pixel(3, 0));
+        assert!(display.pixel(5, 0));
+    }
+
+    #[test]
+    fn test_draw_wraps_around() {
+        let mut display = Display::new();
+        display.draw(62, 31, &[0xF0, 0x80], false);
+        assert!(display.pixel(63, 31));
+        assert!(display.pixel(0, 31));
+        assert!(display.pixel(62, 0));
+    }
+
+    #[test]
+    fn test_draw_clips() {
+        let mut display = Display::new();
+        display.draw(62, 31, &[0xF0, 0x80], true);
+        assert!(display.pixel(62, 31));
+        assert!(display.pixel(63, 31));
+        assert!(!display.pixel(0, 31));
+        assert!(!display.pixel(62, 0));
+    }
+
+    #[test]
+    fn test_draw_start_position_wraps() {
+        let mut display = Display::new();
+        display.draw(66, 33, &[0x80], true);
+        assert!(display.pixel(2, 1));
+    }
+
+    #[test]
+    fn test_set_hires() {
+        let mut display = Display::new();
+        display.draw(0, 0, &[0xFF], true);
+        display.set_hires(true);
+        assert_eq!(display.width(), 128);
+        assert_eq!(display.height(), 64);
+        assert_eq!(display.pixels().len(), 128 * 64);
+        assert!(display.pixels().iter().all(|&p| p == 0));
+        display.draw(120, 60, &[0x01], true);
+        assert!(display.pixel(127, 60));
+        display.set_hires(false);
+        assert_eq!(display.width(), 64);
+        assert_eq!(display.pixels().len(), 64 * 32);
+    }
+
+    #[test]
+    fn test_draw_large() {
+        let mut display = Display::new();
+        display.set_hires(true);
+        let mut sprite = [0u8; 32];
+        sprite[0] = 0x80;
+        sprite[1] = 0x01;
+        sprite[31] = 0x01;
+        let collision = display.draw_large(100, 40, &sprite, true);
+        assert!(!collision);
+        assert!(display.pixel(100, 40));
+        assert!(display.pixel(115, 40));
+        assert!(display.pixel(115, 55));
+        assert!(!display.pixel(101, 40));
+    }
+
+    #[test]
+    fn test_scroll_down() {
+        let mut display = Display::new();
+        display.draw(3, 0, &[0x80], true);
+        display.draw(3, 31, &[0x80], true);
+        display.scroll_down(2);
+        assert!(!display.pixel(3, 0));
+        assert!(display.pixel(3, 2));
+        assert!(!display.pixel(3, 1));
+        assert_eq!(display.pixels().iter().filter(|&&p| p != 0).count(), 1);
+    }
+
+    #[test]
+    fn test_scroll_right_and_left() {
+        let mut display = Display::new();
+        display.draw(0, 5, &[0x80], true);
+        display.draw(63, 6, &[0x80], true);
+        display.scroll_right(4);
+        assert!(display.pixel(4, 5));
+        assert!(!display.pixel(0, 5));
+        assert_eq!(display.pixels().iter().filter(|&&p| p != 0).count(), 1);
+        display.scroll_left(4);
+        assert!(display.pixel(0, 5));
+        assert!(!display.pixel(4, 5));
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut display = Display::new();
+        display.draw(0, 0, &[0xFF], false);
+        display.mark_clean();
+        display.clear();
+        assert!(display.pixels().iter().all(|&p| p == 0));
+        assert!(display.is_dirty());
+    }
+
+    #[test]
+    fn test_scroll_up() {
+        let mut display = Display::new();
+        display.draw(3, 0, &[0x80], true);
+        display.draw(3, 5, &[0x80], true);
+        display.scroll_up(2);
+        assert!(display.pixel(3, 3));
+        assert_eq!(display.pixels().iter().filter(|&&p| p != 0).count(), 1);
+    }
+
+    #[test]
+    fn test_draw_both_planes() {
+        let mut display = Display::new();
+        display.select_planes(0b11);
+        display.draw(0, 0, &[0xC0, 0x80], true);
+        assert_eq!(display.pixel_value(0, 0), 0b11);
+        assert_eq!(display.pixel_value(1, 0), 0b01);
+        assert_eq!(display.color(0, 0), 0x555555);
+        assert_eq!(display.color(1, 0), 0xFFFFFF);
+        assert_eq!(display.color(2, 0), 0x000000);
+    }
+
+    #[test]
+    fn test_draw_second_plane() {
+        let mut display = Display::new();
+        display.draw(0, 0, &[0x80], true);
+        display.select_planes(0b10);
+        let collision = display.draw(0, 0, &[0x80], true);
+        assert!(!collision);
+        assert_eq!(display.pixel_value(0, 0), 0b11);
+        let collision = display.draw(0, 0, &[0x80], true);
+        assert!(collision);
+        assert_eq!(display.pixel_value(0, 0), 0b01);
+    }
+
+    #[test]
+    fn test_draw_no_planes() {
+        let mut display = Display::new();
+        display.select_planes(0);
+        assert!(!display.draw(0, 0, &[0xFF], true));
+        assert!(display.pixels().iter().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn test_clear_and_scroll_selected_planes() {
+        let mut display = Display::new();
+        display.select_planes(0b11);
+        display.draw(0, 0, &[0x80, 0x80], true);
+        display.select_planes(0b10);
+        display.scroll_right(4);
+        assert_eq!(display.pixel_value(0, 0), 0b01);
+        assert_eq!(display.pixel_value(4, 0), 0b10);
+        display.clear();
+        assert_eq!(display.pixel_value(0, 0), 0b01);
+        assert_eq!(display.pixel_value(4, 0), 0);
+    }
+}
@@ -1,11 +1,18 @@
+use std::fmt;
+
 type Addr = u16;
 type Reg = u8;
 type Byte = u8;
 type Nibble = u8;
 
-#[derive(PartialEq, Debug)]
+// Regular opcodes are one big-endian word long
+pub const INSTRUCTION_SIZE: u16 = 2;
+// XO-CHIP's `F000 NNNN` carries its address in a second word
+pub const LONG_INSTRUCTION_PREFIX: u16 = 0xF000;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Instruction {
-    // SYS,
+    Sys(Addr),
     ClearDisplay,
     Return,
     Jump(Addr),
@@ -40,6 +47,24 @@ pub enum Instruction {
     StoreBCD(Reg),
     StoreRegisters(Reg),
     LoadRegisters(Reg),
+    // SUPER-CHIP 1.1
+    ScrollDown(Nibble),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowRes,
+    HighRes,
+    LoadBigSprite(Reg),
+    StoreFlags(Reg),
+    LoadFlags(Reg),
+    // XO-CHIP
+    ScrollUp(Nibble),
+    SaveRange(Reg, Reg),
+    LoadRange(Reg, Reg),
+    LoadLongIndex(Addr),
+    SelectPlanes(Nibble),
+    LoadAudio,
+    SetPitch(Reg),
 }
 
 #[derive(Debug)]
@@ -48,21 +73,55 @@ pub enum InstructionError {
 }
 
 impl Instruction {
+    // Number of bytes taken by the instruction starting with this opcode
+    pub fn size(opcode: u16) -> u16 {
+        if opcode == LONG_INSTRUCTION_PREFIX {
+            2 * INSTRUCTION_SIZE
+        } else {
+            INSTRUCTION_SIZE
+        }
+    }
+
+    // Decodes an instruction that may be followed by an operand word, only
+    // used by XO-CHIP's `F000 NNNN`
+    pub fn new_long(instruction: u16, operand: u16) -> Result<Instruction, InstructionError> {
+        if instruction == LONG_INSTRUCTION_PREFIX {
+            Ok(Instruction::LoadLongIndex(operand))
+        } else {
+            Instruction::new(instruction)
+        }
+    }
+
     pub fn new(instruction: u16) -> Result<Instruction, InstructionError> {
         let x = ((0x0F00 & instruction) >> 8) as u8;
         let y = ((0x00F0 & instruction) >> 4) as u8;
-        let n = ((0x000F & instruction) >> 0) as u8;
-        let kk = ((0x00FF & instruction) >> 0) as u8;
-        let nnn = ((0x0FFF & instruction) >> 0) as u16;
+        let n = (0x000F & instruction) as u8;
+        let kk = (0x00FF & instruction) as u8;
+        let nnn = 0x0FFF & instruction;
 
         match instruction {
             0x00E0 => Ok(Instruction::ClearDisplay),
             0x00EE => Ok(Instruction::Return),
+            0x00C0..=0x00CF => Ok(Instruction::ScrollDown(n)),
+            0x00D0..=0x00DF => Ok(Instruction::ScrollUp(n)),
+            0x00FB => Ok(Instruction::ScrollRight),
+            0x00FC => Ok(Instruction::ScrollLeft),
+            0x00FD => Ok(Instruction::Exit),
+            0x00FE => Ok(Instruction::LowRes),
+            0x00FF => Ok(Instruction::HighRes),
+            0x0000..=0x0FFF => Ok(Instruction::Sys(nnn)),
             0x1000..=0x1FFF => Ok(Instruction::Jump(nnn)),
             0x2000..=0x2FFF => Ok(Instruction::Call(nnn)),
             0x3000..=0x3FFF => Ok(Instruction::SkipIfEqualsByte(x, kk)),
             0x4000..=0x4FFF => Ok(Instruction::SkipIfNotEqualsByte(x, kk)),
-            0x5000..=0x5FFF => Ok(Instruction::SkipIfEqualsRegister(x, y)),
+            0x5000..=0x5FFF => {
+                match n {
+                    0x0 => Ok(Instruction::SkipIfEqualsRegister(x, y)),
+                    0x2 => Ok(Instruction::SaveRange(x, y)),
+                    0x3 => Ok(Instruction::LoadRange(x, y)),
+                    _ => Err(InstructionError::InvalidInstruction)
+                }
+            },
             0x6000..=0x6FFF => Ok(Instruction::LoadByte(x, kk)),
             0x7000..=0x7FFF => Ok(Instruction::AddByte(x, kk)),
             0x8000..=0x8FFF => {
@@ -91,21 +150,162 @@ impl Instruction {
                     _ => Err(InstructionError::InvalidInstruction)
                 }
             },
+            0xF002 => Ok(Instruction::LoadAudio),
             0xF000..=0xFFFF => {
                 match kk {
+                    0x01 => Ok(Instruction::SelectPlanes(x)),
                     0x07 => Ok(Instruction::LoadDelayTimer(x)),
                     0x0A => Ok(Instruction::WaitKeyPress(x)),
                     0x15 => Ok(Instruction::StoreDelayTimer(x)),
                     0x18 => Ok(Instruction::StoreSoundTimer(x)),
                     0x1E => Ok(Instruction::AddToIndex(x)),
                     0x29 => Ok(Instruction::LoadSprite(x)),
+                    0x30 => Ok(Instruction::LoadBigSprite(x)),
+                    0x3A => Ok(Instruction::SetPitch(x)),
                     0x33 => Ok(Instruction::StoreBCD(x)),
                     0x55 => Ok(Instruction::StoreRegisters(x)),
                     0x65 => Ok(Instruction::LoadRegisters(x)),
+                    0x75 => Ok(Instruction::StoreFlags(x)),
+                    0x85 => Ok(Instruction::LoadFlags(x)),
                     _ => Err(InstructionError::InvalidInstruction)
                 }
             },
-            _ => Err(InstructionError::InvalidInstruction),
+        }
+    }
+
+    // Inverse of `new`, for XO-CHIP's `F000 NNNN` this is only the first
+    // word and `encode_words` gives both
+    pub fn encode(&self) -> u16 {
+        let xy = |prefix: u16, x: Reg, y: Reg, n: u16| {
+            prefix | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | n
+        };
+        let xkk = |prefix: u16, x: Reg, kk: Byte| prefix | (x as u16 & 0xF) << 8 | kk as u16;
+        let x = |prefix: u16, x: Reg, kk: u16| prefix | (x as u16 & 0xF) << 8 | kk;
+
+        match *self {
+            Instruction::Sys(addr) => addr & 0x0FFF,
+            Instruction::ClearDisplay => 0x00E0,
+            Instruction::Return => 0x00EE,
+            Instruction::Jump(addr) => 0x1000 | addr & 0x0FFF,
+            Instruction::Call(addr) => 0x2000 | addr & 0x0FFF,
+            Instruction::SkipIfEqualsByte(vx, kk) => xkk(0x3000, vx, kk),
+            Instruction::SkipIfNotEqualsByte(vx, kk) => xkk(0x4000, vx, kk),
+            Instruction::SkipIfEqualsRegister(vx, vy) => xy(0x5000, vx, vy, 0x0),
+            Instruction::LoadByte(vx, kk) => xkk(0x6000, vx, kk),
+            Instruction::AddByte(vx, kk) => xkk(0x7000, vx, kk),
+            Instruction::Move(vx, vy) => xy(0x8000, vx, vy, 0x0),
+            Instruction::Or(vx, vy) => xy(0x8000, vx, vy, 0x1),
+            Instruction::And(vx, vy) => xy(0x8000, vx, vy, 0x2),
+            Instruction::Xor(vx, vy) => xy(0x8000, vx, vy, 0x3),
+            Instruction::Add(vx, vy) => xy(0x8000, vx, vy, 0x4),
+            Instruction::Subtract(vx, vy) => xy(0x8000, vx, vy, 0x5),
+            Instruction::ShiftRight(vx, vy) => xy(0x8000, vx, vy, 0x6),
+            Instruction::SubtractReverse(vx, vy) => xy(0x8000, vx, vy, 0x7),
+            Instruction::ShifLeft(vx, vy) => xy(0x8000, vx, vy, 0xE),
+            Instruction::SkipIfNotEqualsRegister(vx, vy) => xy(0x9000, vx, vy, 0x0),
+            Instruction::LoadIndex(addr) => 0xA000 | addr & 0x0FFF,
+            Instruction::JumpWithOffset(addr) => 0xB000 | addr & 0x0FFF,
+            Instruction::RandomWithMask(vx, kk) => xkk(0xC000, vx, kk),
+            Instruction::Draw(vx, vy, n) => xy(0xD000, vx, vy, n as u16 & 0xF),
+            Instruction::SkipIfPressed(vx) => x(0xE000, vx, 0x9E),
+            Instruction::SkipIfNotPressed(vx) => x(0xE000, vx, 0xA1),
+            Instruction::LoadDelayTimer(vx) => x(0xF000, vx, 0x07),
+            Instruction::WaitKeyPress(vx) => x(0xF000, vx, 0x0A),
+            Instruction::StoreDelayTimer(vx) => x(0xF000, vx, 0x15),
+            Instruction::StoreSoundTimer(vx) => x(0xF000, vx, 0x18),
+            Instruction::AddToIndex(vx) => x(0xF000, vx, 0x1E),
+            Instruction::LoadSprite(vx) => x(0xF000, vx, 0x29),
+            Instruction::StoreBCD(vx) => x(0xF000, vx, 0x33),
+            Instruction::StoreRegisters(vx) => x(0xF000, vx, 0x55),
+            Instruction::LoadRegisters(vx) => x(0xF000, vx, 0x65),
+            Instruction::ScrollDown(n) => 0x00C0 | n as u16 & 0xF,
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowRes => 0x00FE,
+            Instruction::HighRes => 0x00FF,
+            Instruction::LoadBigSprite(vx) => x(0xF000, vx, 0x30),
+            Instruction::StoreFlags(vx) => x(0xF000, vx, 0x75),
+            Instruction::LoadFlags(vx) => x(0xF000, vx, 0x85),
+            Instruction::ScrollUp(n) => 0x00D0 | n as u16 & 0xF,
+            Instruction::SaveRange(vx, vy) => xy(0x5000, vx, vy, 0x2),
+            Instruction::LoadRange(vx, vy) => xy(0x5000, vx, vy, 0x3),
+            Instruction::LoadLongIndex(_) => LONG_INSTRUCTION_PREFIX,
+            Instruction::SelectPlanes(n) => x(0xF000, n, 0x01),
+            Instruction::LoadAudio => 0xF002,
+            Instruction::SetPitch(vx) => x(0xF000, vx, 0x3A),
+        }
+    }
+
+    // All the words of the instruction, as they are laid out in memory
+    pub fn encode_words(&self) -> Vec<u16> {
+        match *self {
+            Instruction::LoadLongIndex(addr) => vec![LONG_INSTRUCTION_PREFIX, addr],
+            _ => vec![self.encode()],
+        }
+    }
+
+    // Big-endian bytes of `encode_words`
+    pub fn encode_bytes(&self) -> Vec<u8> {
+        self.encode_words().iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+}
+
+// Cowgod-style mnemonics, e.g. `SHL V4, V2`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Sys(addr) => write!(f, "SYS 0x{:03X}", addr),
+            Instruction::ClearDisplay => write!(f, "CLS"),
+            Instruction::Return => write!(f, "RET"),
+            Instruction::Jump(addr) => write!(f, "JP 0x{:03X}", addr),
+            Instruction::Call(addr) => write!(f, "CALL 0x{:03X}", addr),
+            Instruction::SkipIfEqualsByte(x, kk) => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SkipIfNotEqualsByte(x, kk) => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SkipIfEqualsRegister(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LoadByte(x, kk) => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            Instruction::AddByte(x, kk) => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            Instruction::Move(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::Add(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Subtract(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubtractReverse(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShifLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipIfNotEqualsRegister(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LoadIndex(addr) => write!(f, "LD I, 0x{:03X}", addr),
+            Instruction::JumpWithOffset(addr) => write!(f, "JP V0, 0x{:03X}", addr),
+            Instruction::RandomWithMask(x, kk) => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Instruction::Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipIfPressed(x) => write!(f, "SKP V{:X}", x),
+            Instruction::SkipIfNotPressed(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LoadDelayTimer(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitKeyPress(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::StoreDelayTimer(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::StoreSoundTimer(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddToIndex(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LoadSprite(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::StoreBCD(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::StoreRegisters(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LoadRegisters(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::ScrollDown(n) => write!(f, "SCD {}", n),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::LowRes => write!(f, "LOW"),
+            Instruction::HighRes => write!(f, "HIGH"),
+            Instruction::LoadBigSprite(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::ScrollUp(n) => write!(f, "SCU {}", n),
+            Instruction::SaveRange(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::LoadRange(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::LoadLongIndex(addr) => write!(f, "LD I, LONG 0x{:04X}", addr),
+            Instruction::SelectPlanes(n) => write!(f, "PLANE {}", n),
+            Instruction::LoadAudio => write!(f, "AUDIO"),
+            Instruction::SetPitch(x) => write!(f, "PITCH V{:X}", x),
         }
     }
 }
@@ -114,6 +314,28 @@ impl Instruction {
 mod tests {
     use super::Instruction;
 
+    #[test]
+    fn test_size() {
+        assert_eq!(Instruction::size(0x00E0), 2);
+        assert_eq!(Instruction::size(0xF065), 2);
+        assert_eq!(Instruction::size(0xF000), 4);
+    }
+
+    #[test]
+    fn test_decode_sys() {
+        let instruction = Instruction::new(0x0123).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::Sys(0x0123));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(Instruction::new(0x800F).is_err());
+        assert!(Instruction::new(0xE000).is_err());
+        assert!(Instruction::new(0xF0FF).is_err());
+        assert!(Instruction::new(0x5121).is_err());
+        assert!(Instruction::new(0xF000).is_err());
+    }
+
     #[test]
     fn test_decode_clear_display() {
         let instruction = Instruction::new(0x00E0).expect("Error decoding instruction");
@@ -152,7 +374,7 @@ mod tests {
 
     #[test]
     fn test_decode_skip_if_equals_register() {
-        let instruction = Instruction::new(0x5BC2).expect("Error decoding instruction");
+        let instruction = Instruction::new(0x5BC0).expect("Error decoding instruction");
         assert_eq!(instruction, Instruction::SkipIfEqualsRegister(0xB, 0xC));
     }
 
@@ -317,4 +539,152 @@ mod tests {
         let instruction = Instruction::new(0xF965).expect("Error decoding instruction");
         assert_eq!(instruction, Instruction::LoadRegisters(0x9));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_decode_scroll_down() {
+        let instruction = Instruction::new(0x00C7).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::ScrollDown(0x7));
+    }
+
+    #[test]
+    fn test_decode_scroll_right() {
+        let instruction = Instruction::new(0x00FB).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::ScrollRight);
+    }
+
+    #[test]
+    fn test_decode_scroll_left() {
+        let instruction = Instruction::new(0x00FC).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::ScrollLeft);
+    }
+
+    #[test]
+    fn test_decode_exit() {
+        let instruction = Instruction::new(0x00FD).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::Exit);
+    }
+
+    #[test]
+    fn test_decode_low_res() {
+        let instruction = Instruction::new(0x00FE).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::LowRes);
+    }
+
+    #[test]
+    fn test_decode_high_res() {
+        let instruction = Instruction::new(0x00FF).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::HighRes);
+    }
+
+    #[test]
+    fn test_decode_draw_large() {
+        let instruction = Instruction::new(0xD120).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::Draw(0x1, 0x2, 0x0));
+    }
+
+    #[test]
+    fn test_decode_load_big_sprite() {
+        let instruction = Instruction::new(0xF330).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::LoadBigSprite(0x3));
+    }
+
+    #[test]
+    fn test_decode_store_flags() {
+        let instruction = Instruction::new(0xF775).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::StoreFlags(0x7));
+    }
+
+    #[test]
+    fn test_decode_load_flags() {
+        let instruction = Instruction::new(0xF585).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::LoadFlags(0x5));
+    }
+
+    #[test]
+    fn test_decode_scroll_up() {
+        let instruction = Instruction::new(0x00D4).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::ScrollUp(0x4));
+    }
+
+    #[test]
+    fn test_decode_save_range() {
+        let instruction = Instruction::new(0x5362).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::SaveRange(0x3, 0x6));
+    }
+
+    #[test]
+    fn test_decode_load_range() {
+        let instruction = Instruction::new(0x5A23).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::LoadRange(0xA, 0x2));
+    }
+
+    #[test]
+    fn test_decode_load_long_index() {
+        let instruction = Instruction::new_long(0xF000, 0xBEEF).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::LoadLongIndex(0xBEEF));
+    }
+
+    #[test]
+    fn test_decode_new_long_short_instruction() {
+        let instruction = Instruction::new_long(0x6312, 0xBEEF).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::LoadByte(0x3, 0x12));
+    }
+
+    #[test]
+    fn test_decode_select_planes() {
+        let instruction = Instruction::new(0xF301).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::SelectPlanes(0x3));
+    }
+
+    #[test]
+    fn test_decode_load_audio() {
+        let instruction = Instruction::new(0xF002).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::LoadAudio);
+    }
+
+    #[test]
+    fn test_decode_set_pitch() {
+        let instruction = Instruction::new(0xF83A).expect("Error decoding instruction");
+        assert_eq!(instruction, Instruction::SetPitch(0x8));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Instruction::ShifLeft(4, 2).to_string(), "SHL V4, V2");
+        assert_eq!(Instruction::LoadByte(0xA, 0x0F).to_string(), "LD VA, 0x0F");
+        assert_eq!(Instruction::Draw(0, 1, 5).to_string(), "DRW V0, V1, 5");
+        assert_eq!(Instruction::LoadIndex(0x2A0).to_string(), "LD I, 0x2A0");
+        assert_eq!(Instruction::StoreRegisters(3).to_string(), "LD [I], V3");
+        assert_eq!(Instruction::LoadLongIndex(0xF000).to_string(), "LD I, LONG 0xF000");
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(Instruction::ShifLeft(4, 2).encode(), 0x842E);
+        assert_eq!(Instruction::Draw(0xA, 0xB, 0xC).encode(), 0xDABC);
+        assert_eq!(Instruction::LoadRegisters(0xF).encode(), 0xFF65);
+        assert_eq!(Instruction::LoadLongIndex(0x1234).encode(), 0xF000);
+        assert_eq!(Instruction::LoadLongIndex(0x1234).encode_words(), [0xF000, 0x1234]);
+        assert_eq!(Instruction::Jump(0x2A0).encode_bytes(), [0x12, 0xA0]);
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        for opcode in 0..=u16::MAX {
+            if let Ok(instruction) = Instruction::new(opcode) {
+                // `9xyn` decodes for any n, so compare instructions rather than opcodes
+                assert_eq!(Instruction::new(instruction.encode()).ok(), Some(instruction));
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_long_round_trip() {
+        for addr in 0..=u16::MAX {
+            let instruction = Instruction::LoadLongIndex(addr);
+            let words = instruction.encode_words();
+            assert_eq!(words.len() as u16 * 2, Instruction::size(words[0]));
+            assert_eq!(Instruction::new_long(words[0], words[1]).ok(), Some(instruction));
+        }
+    }
+}
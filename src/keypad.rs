@@ -0,0 +1,76 @@
+pub const KEY_COUNT: usize = 16;
+
+// State of the 16-key hexadecimal keypad, keys 0x0 to 0xF
+pub struct Keypad {
+    keys: [bool; KEY_COUNT],
+}
+
+impl Keypad {
+    pub fn new() -> Self {
+        Keypad {
+            keys: [false; KEY_COUNT],
+        }
+    }
+
+    // Keys are masked to 0x0-0xF, the way Ex9E/ExA1 read them from Vx
+    pub fn press(&mut self, key: u8) {
+        self.keys[key as usize & 0xF] = true;
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.keys[key as usize & 0xF] = false;
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.keys[key as usize & 0xF]
+    }
+
+    // Lowest numbered key currently held down, if any
+    pub fn first_pressed(&self) -> Option<u8> {
+        self.keys.iter().position(|&k| k).map(|k| k as u8)
+    }
+
+    pub fn keys(&self) -> &[bool; KEY_COUNT] {
+        &self.keys
+    }
+}
+
+impl Default for Keypad {
+    fn default() -> Self {
+        Keypad::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Keypad;
+
+    #[test]
+    fn test_press_and_release() {
+        let mut keypad = Keypad::new();
+        keypad.press(0xA);
+        assert!(keypad.is_pressed(0xA));
+        assert!(!keypad.is_pressed(0xB));
+        keypad.release(0xA);
+        assert!(!keypad.is_pressed(0xA));
+    }
+
+    #[test]
+    fn test_key_out_of_range() {
+        let mut keypad = Keypad::new();
+        keypad.press(0x1A);
+        assert!(keypad.is_pressed(0xA));
+        assert!(keypad.is_pressed(0xFA));
+        keypad.release(0xFA);
+        assert!(!keypad.is_pressed(0xA));
+    }
+
+    #[test]
+    fn test_first_pressed() {
+        let mut keypad = Keypad::new();
+        assert_eq!(keypad.first_pressed(), None);
+        keypad.press(0xC);
+        keypad.press(0x3);
+        assert_eq!(keypad.first_pressed(), Some(0x3));
+    }
+}
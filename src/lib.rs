@@ -0,0 +1,20 @@
+pub mod analysis;
+pub mod assembler;
+pub mod audio;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod display;
+pub mod font;
+pub mod instruction;
+pub mod keypad;
+pub mod machine;
+pub mod movie;
+pub mod quirks;
+pub mod ram;
+pub mod rewind;
+pub mod rng;
+pub mod runner;
+pub mod state;
+pub mod tracer;
+pub mod variant;
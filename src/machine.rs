@@ -0,0 +1,749 @@
+use std::fmt;
+use crate::cpu::{Cpu, CpuError};
+use crate::disassembler::disassemble_at;
+use crate::display::Display;
+use crate::instruction::{Instruction, INSTRUCTION_SIZE};
+use crate::keypad::Keypad;
+use crate::quirks::Quirks;
+use crate::ram::{Access, Hit, LoadError, Ram, WatchAction};
+use crate::rng::{RandomSource, SplitMix64};
+use crate::state::{StateError, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
+use crate::tracer::{TraceEntry, Tracer};
+use crate::variant::Variant;
+
+// Rate at which the delay and sound timers count down
+pub const TIMER_FREQUENCY: u32 = 60;
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+// A watchpoint hit along with the instruction that caused it
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct WatchEvent {
+    pub hit: Hit,
+    pub pc: u16,
+    pub instruction: Option<Instruction>,
+}
+
+impl fmt::Display for WatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hit = &self.hit;
+        match hit.access {
+            Access::Read => write!(f, "read {:02X} from {:04X}", hit.value, hit.address)?,
+            Access::Write => write!(f, "write {:02X} to {:04X}", hit.value, hit.address)?,
+            Access::Execute => write!(f, "execute {:04X} at {:04X}", hit.value, hit.address)?,
+        }
+        write!(f, " by PC {:04X}", self.pc)?;
+        match self.instruction {
+            Some(instruction) => write!(f, " ({})", instruction),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct Machine {
+    cpu: Cpu,
+    ram: Ram,
+    instructions_per_frame: u32,
+    frame_cycles: u32,
+    frames: u64,
+    cycles: u64,
+    watch_events: Vec<WatchEvent>,
+    watch_stop: Option<WatchEvent>,
+    tracer: Option<Tracer>,
+}
+
+impl Machine {
+    pub fn new(ram: Ram) -> Self {
+        let mut cpu = Cpu::new();
+        cpu.set_pc(ram.program_start());
+        Machine {
+            cpu,
+            ram,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            frame_cycles: 0,
+            frames: 0,
+            cycles: 0,
+            watch_events: Vec::new(),
+            watch_stop: None,
+            tracer: None,
+        }
+    }
+
+    // Machine for the variant with the ROM loaded into memory of the right size
+    pub fn for_variant(variant: Variant, rom: &[u8]) -> Result<Self, LoadError> {
+        let mut ram = Ram::with_size(variant.memory_size());
+        ram.load_rom(rom)?;
+        let mut machine = Machine::new(ram);
+        machine.set_variant(variant);
+        Ok(machine)
+    }
+
+    pub fn with_seed(ram: Ram, seed: u64) -> Self {
+        let mut machine = Machine::new(ram);
+        machine.set_rng(Box::new(SplitMix64::new(seed)));
+        machine
+    }
+
+    pub fn set_rng(&mut self, rng: Box<dyn RandomSource>) {
+        self.cpu.set_rng(rng);
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.cpu.seed()
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.cpu.variant()
+    }
+
+    // Also switches to the variant's default quirks
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.cpu.set_variant(variant);
+        self.cpu.set_quirks(variant.quirks());
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.cpu.quirks()
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.cpu.set_quirks(quirks);
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    // How many instructions run between two 60 Hz timer ticks
+    pub fn set_instructions_per_frame(&mut self, instructions: u32) {
+        assert!(instructions > 0, "A frame needs at least one instruction");
+        self.instructions_per_frame = instructions;
+    }
+
+    // Instructions executed per second of emulated time
+    pub fn clock_speed(&self) -> u32 {
+        self.instructions_per_frame * TIMER_FREQUENCY
+    }
+
+    // Number of steps taken so far, including the ones where the CPU was
+    // halted or waiting on a key or the display and didn't execute anything
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Number of completed 60 Hz frames
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn sound_active(&self) -> bool {
+        self.cpu.sound_active()
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.cpu.is_halted()
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut Ram {
+        &mut self.ram
+    }
+
+    pub fn display(&self) -> &Display {
+        self.cpu.display()
+    }
+
+    pub fn display_mut(&mut self) -> &mut Display {
+        self.cpu.display_mut()
+    }
+
+    pub fn keypad(&self) -> &Keypad {
+        self.cpu.keypad()
+    }
+
+    pub fn keypad_mut(&mut self) -> &mut Keypad {
+        self.cpu.keypad_mut()
+    }
+
+    // Records every executed instruction from now on
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Stops tracing, the tracer still has to be finished
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    // Events of logging watchpoints since the last call
+    pub fn take_watch_events(&mut self) -> Vec<WatchEvent> {
+        std::mem::take(&mut self.watch_events)
+    }
+
+    // The first hit of a stopping watchpoint, which has to be taken before
+    // `run_until` goes on
+    pub fn watch_stop(&self) -> Option<&WatchEvent> {
+        self.watch_stop.as_ref()
+    }
+
+    pub fn take_watch_stop(&mut self) -> Option<WatchEvent> {
+        self.watch_stop.take()
+    }
+
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        let waiting_key = self.cpu.is_waiting_key();
+        // PC is already past an Fx0A that's waiting, which still gets traced
+        // when the wait ends and it loads the register
+        let pc = if waiting_key { self.cpu.pc().wrapping_sub(INSTRUCTION_SIZE) } else { self.cpu.pc() };
+        let tracing = self.tracer.is_some()
+            && !(self.cpu.is_halted() || (self.cpu.is_waiting_display() && !waiting_key));
+        // Decoded up front in case the instruction overwrites itself
+        let line = if tracing || !self.ram.watchpoints().is_empty() {
+            disassemble_at(&self.ram, pc)
+        } else {
+            None
+        };
+        let instruction = line.as_ref().and_then(|line| line.instruction);
+        let registers = *self.cpu.registers();
+        let result = self.cpu.cycle(&mut self.ram);
+        for hit in self.ram.take_hits() {
+            let event = WatchEvent { hit, pc, instruction };
+            match hit.action {
+                WatchAction::Log => self.watch_events.push(event),
+                WatchAction::Stop => self.watch_stop = self.watch_stop.or(Some(event)),
+            }
+        }
+        let tracing = tracing && !(waiting_key && self.cpu.is_waiting_key());
+        if let (true, Some(tracer), Some(line)) = (tracing, &mut self.tracer, line) {
+            let cpu = &self.cpu;
+            let changed = (0..16u8)
+                .filter(|&reg| cpu.registers()[reg as usize] != registers[reg as usize])
+                .map(|reg| (reg, cpu.registers()[reg as usize]))
+                .collect();
+            tracer.record(&TraceEntry {
+                cycle: self.cycles,
+                pc,
+                opcode: line.opcode,
+                operand: line.operand,
+                instruction,
+                changed,
+                i: cpu.i(),
+                sp: cpu.sp(),
+                delay_timer: cpu.delay_timer(),
+                sound_timer: cpu.sound_timer(),
+                error: result.as_ref().err().map(|error| error.to_string()),
+            });
+        }
+        result?;
+        self.cycles += 1;
+        self.frame_cycles += 1;
+        if self.frame_cycles >= self.instructions_per_frame {
+            self.end_frame();
+        }
+        Ok(())
+    }
+
+    // Runs the remaining instructions of the current frame
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        let frame = self.frames;
+        while self.frames == frame {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    fn end_frame(&mut self) {
+        self.cpu.tick_timers();
+        self.cpu.vblank();
+        self.frame_cycles = 0;
+        self.frames += 1;
+    }
+
+    pub fn run_cycles(&mut self, cycles: usize) -> Result<(), CpuError> {
+        for _ in 0..cycles {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    // Snapshot of the whole machine, restored with load_state
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.bytes(&STATE_MAGIC);
+        state.u16(STATE_VERSION);
+        state.variant(self.variant());
+        state.u32(self.instructions_per_frame);
+        state.u32(self.frame_cycles);
+        state.u64(self.frames);
+        state.u64(self.cycles);
+        self.cpu.write_state(&mut state);
+        self.ram.write_state(&mut state);
+        state.into_bytes()
+    }
+
+    // Leaves the machine untouched if the state can't be loaded. Watchpoints
+    // and the tracer are kept, as is the random source when the state has none
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        if state.array::<4>().ok() != Some(STATE_MAGIC) {
+            return Err(StateError::NotASaveState);
+        }
+        let version = state.u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion { version });
+        }
+        let found = state.variant()?;
+        if found != self.variant() {
+            return Err(StateError::VariantMismatch { expected: self.variant(), found });
+        }
+        let instructions_per_frame = state.u32()?;
+        if instructions_per_frame == 0 {
+            return Err(StateError::Corrupt("no instructions per frame"));
+        }
+        let frame_cycles = state.u32()?;
+        let frames = state.u64()?;
+        let cycles = state.u64()?;
+        let (mut cpu, restored) = Cpu::read_state(&mut state)?;
+        if cpu.variant() != found {
+            return Err(StateError::VariantMismatch { expected: found, found: cpu.variant() });
+        }
+        let mut ram = Ram::read_state(&mut state)?;
+        if ram.size() != found.memory_size() {
+            return Err(StateError::Corrupt("memory size doesn't match the variant"));
+        }
+        if !state.is_empty() {
+            return Err(StateError::Corrupt("trailing data"));
+        }
+
+        if !restored {
+            cpu.set_rng(self.cpu.take_rng());
+        }
+        for watchpoint in self.ram.watchpoints() {
+            ram.add_watchpoint(watchpoint.clone());
+        }
+        self.cpu = cpu;
+        self.ram = ram;
+        self.instructions_per_frame = instructions_per_frame;
+        self.frame_cycles = frame_cycles;
+        self.frames = frames;
+        self.cycles = cycles;
+        self.watch_events.clear();
+        self.watch_stop = None;
+        Ok(())
+    }
+
+    // Steps until the predicate holds, the program exits or a watchpoint stops
+    // it, returning how many cycles were executed
+    pub fn run_until<F>(&mut self, mut predicate: F) -> Result<usize, CpuError>
+    where
+        F: FnMut(&Machine) -> bool,
+    {
+        let mut cycles = 0;
+        while !predicate(self) && !self.is_halted() && self.watch_stop.is_none() {
+            self.step()?;
+            cycles += 1;
+        }
+        Ok(cycles)
+    }
+}
+
+// State dump of the CPU, for logs and bug reports
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cpu = &self.cpu;
+        writeln!(
+            f,
+            "PC: {:04X}  I: {:04X}  SP: {:X}  DT: {:02X}  ST: {:02X}",
+            cpu.pc(), cpu.i(), cpu.sp(), cpu.delay_timer(), cpu.sound_timer()
+        )?;
+        for (reg, val) in cpu.registers().iter().enumerate() {
+            write!(f, "V{:X}: {:02X}", reg, val)?;
+            f.write_str(if reg % 8 == 7 { "\n" } else { "  " })?;
+        }
+        write!(f, "Stack:")?;
+        for addr in cpu.stack() {
+            write!(f, " {:04X}", addr)?;
+        }
+        writeln!(f)?;
+        match self.seed() {
+            Some(seed) => writeln!(f, "Seed: {}", seed)?,
+            None => writeln!(f, "Seed: custom")?,
+        }
+        write!(f, "Cycles: {}  Frames: {}", self.cycles, self.frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Machine, DEFAULT_INSTRUCTIONS_PER_FRAME};
+    use crate::cpu::CpuError;
+    use crate::instruction::Instruction;
+    use crate::quirks::Quirks;
+    use crate::variant::Variant;
+    use crate::ram::{Access, Ram, WatchAction, Watchpoint};
+    use crate::state::{StateError, STATE_VERSION};
+    use crate::tracer::testing::Buffer;
+    use crate::tracer::{TraceFormat, Tracer};
+
+    fn machine(rom: &[u8]) -> Machine {
+        Machine::new(Ram::from_bytes(rom).expect("Error loading ROM"))
+    }
+
+    #[test]
+    fn test_starts_at_program_start() {
+        let machine = Machine::new(Ram::empty());
+        assert_eq!(machine.cpu().pc(), 0x200);
+    }
+
+    #[test]
+    fn test_starts_at_load_address() {
+        let mut ram = Ram::empty();
+        ram.load_rom_at(&[0x16, 0x00], 0x600).expect("Error loading ROM");
+        let mut machine = Machine::new(ram);
+        assert_eq!(machine.cpu().pc(), 0x600);
+        machine.step().expect("Error stepping the machine");
+        assert_eq!(machine.cpu().pc(), 0x600);
+    }
+
+    #[test]
+    fn test_step() {
+        let mut machine = machine(&[0x12, 0x04]);
+        machine.step().expect("Error stepping the machine");
+        assert_eq!(machine.cpu().pc(), 0x204);
+    }
+
+    #[test]
+    fn test_step_invalid_instruction() {
+        let mut machine = machine(&[0xFF, 0xFF]);
+        let result = machine.step();
+        assert_eq!(result, Err(CpuError::InvalidOpcode { opcode: 0xFFFF, pc: 0x200 }));
+    }
+
+    #[test]
+    fn test_run_until_stops_on_error() {
+        // CALL 0x200, recursing until the stack overflows
+        let mut machine = machine(&[0x22, 0x00]);
+        let result = machine.run_until(|_| false);
+        assert_eq!(result, Err(CpuError::StackOverflow));
+        assert_eq!(machine.cycles(), 16);
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        let mut machine = machine(&[0x12, 0x04, 0x00, 0x00, 0x12, 0x08, 0x00, 0x00, 0x12, 0x00]);
+        machine.run_cycles(2).expect("Error running the machine");
+        assert_eq!(machine.cpu().pc(), 0x208);
+        machine.run_cycles(1).expect("Error running the machine");
+        assert_eq!(machine.cpu().pc(), 0x200);
+    }
+
+    #[test]
+    fn test_step_call_and_return() {
+        let mut machine = machine(&[0x22, 0x04, 0x12, 0x02, 0x00, 0xEE]);
+        machine.step().expect("Error stepping the machine");
+        assert_eq!(machine.cpu().pc(), 0x204);
+        machine.step().expect("Error stepping the machine");
+        assert_eq!(machine.cpu().pc(), 0x202);
+    }
+
+    #[test]
+    fn test_draw_to_display() {
+        // LD I, 0x206; DRW V0, V0, 1; JP 0x204; sprite
+        let mut machine = machine(&[0xA2, 0x06, 0xD0, 0x01, 0x12, 0x04, 0x80]);
+        machine.run_cycles(2).expect("Error running the machine");
+        assert!(machine.display().pixel(0, 0));
+        assert!(!machine.display().pixel(1, 0));
+        assert!(machine.display().is_dirty());
+    }
+
+    #[test]
+    fn test_wait_key_press() {
+        // LD V2, K; JP 0x202
+        let mut machine = machine(&[0xF2, 0x0A, 0x12, 0x02]);
+        machine.run_cycles(3).expect("Error running the machine");
+        assert_eq!(machine.cpu().pc(), 0x202);
+        machine.keypad_mut().press(0xE);
+        machine.step().expect("Error stepping the machine");
+        machine.keypad_mut().release(0xE);
+        machine.step().expect("Error stepping the machine");
+        assert_eq!(machine.cpu().registers()[2], 0xE);
+        machine.step().expect("Error stepping the machine");
+        assert_eq!(machine.cpu().pc(), 0x202);
+    }
+
+    #[test]
+    fn test_timers_tick_once_per_frame() {
+        // LD V0, 0x03; LD DT, V0; LD ST, V0; JP 0x206
+        let mut machine = machine(&[0x60, 0x03, 0xF0, 0x15, 0xF0, 0x18, 0x12, 0x06]);
+        machine.set_instructions_per_frame(4);
+        machine.run_cycles(3).expect("Error running the machine");
+        assert_eq!(machine.cpu().delay_timer(), 3);
+        assert!(machine.sound_active());
+        machine.step().expect("Error stepping the machine");
+        assert_eq!(machine.frames(), 1);
+        assert_eq!(machine.cpu().delay_timer(), 2);
+        machine.run_frame().expect("Error running the machine");
+        machine.run_frame().expect("Error running the machine");
+        assert_eq!(machine.frames(), 3);
+        assert_eq!(machine.cpu().delay_timer(), 0);
+        assert!(!machine.sound_active());
+    }
+
+    #[test]
+    fn test_wait_on_delay_timer() {
+        // LD V0, 0x02; LD DT, V0; LD V1, DT; SE V1, 0; JP 0x204; JP 0x20A
+        let mut machine = machine(&[
+            0x60, 0x02, 0xF0, 0x15, 0xF1, 0x07, 0x31, 0x00, 0x12, 0x04, 0x12, 0x0A,
+        ]);
+        let cycles = machine
+            .run_until(|m| m.cpu().pc() == 0x20A)
+            .expect("Error running the machine");
+        assert!(cycles > 2 * DEFAULT_INSTRUCTIONS_PER_FRAME as usize);
+        assert_eq!(machine.frames(), 2);
+    }
+
+    #[test]
+    fn test_draw_font_glyph() {
+        // LD V0, 0x01; LD F, V0; DRW V1, V1, 5
+        let mut machine = machine(&[0x60, 0x01, 0xF0, 0x29, 0xD1, 0x15]);
+        machine.run_cycles(3).expect("Error running the machine");
+        let column: Vec<bool> = (0..5).map(|y| machine.display().pixel(2, y)).collect();
+        assert_eq!(column, vec![true, true, true, true, true]);
+        assert!(machine.display().pixel(1, 1));
+        assert!(!machine.display().pixel(1, 0));
+    }
+
+    #[test]
+    fn test_store_and_load_registers() {
+        // LD V0, 123; LD I, 0x300; LD B, V0; LD V2, [I]
+        let mut machine = machine(&[0x60, 0x7B, 0xA3, 0x00, 0xF0, 0x33, 0xF2, 0x65]);
+        machine.run_cycles(4).expect("Error running the machine");
+        assert_eq!(machine.cpu().registers()[..3], [1, 2, 3]);
+        assert_eq!(machine.ram().read_u8(0x302), Ok(3));
+    }
+
+    #[test]
+    fn test_random_is_reproducible() {
+        // RND V0, 0xFF; RND V1, 0xFF; RND V2, 0x0F
+        let rom = [0xC0, 0xFF, 0xC1, 0xFF, 0xC2, 0x0F];
+        let mut a = Machine::with_seed(Ram::from_bytes(&rom).expect("Error loading ROM"), 7);
+        let mut b = Machine::with_seed(Ram::from_bytes(&rom).expect("Error loading ROM"), 7);
+        a.run_cycles(3).expect("Error running the machine");
+        b.run_cycles(3).expect("Error running the machine");
+        assert_eq!(a.cpu().registers(), b.cpu().registers());
+        assert!(a.cpu().registers()[2] <= 0x0F);
+    }
+
+    #[test]
+    fn test_state_dump() {
+        let machine = Machine::with_seed(Ram::empty(), 1234);
+        let dump = machine.to_string();
+        assert!(dump.contains("PC: 0200"));
+        assert!(dump.contains("VF: 00"));
+        assert!(dump.contains("Seed: 1234"));
+    }
+
+    #[test]
+    fn test_display_wait_quirk() {
+        // DRW V0, V0, 0; DRW V0, V0, 0; JP 0x204
+        let rom = [0xD0, 0x00, 0xD0, 0x00, 0x12, 0x04];
+        let mut vip = machine(&rom);
+        vip.set_quirks(Quirks::cosmac_vip());
+        vip.run_until(|m| m.cpu().pc() == 0x204).expect("Error running the machine");
+        assert_eq!(vip.frames(), 1);
+
+        let mut schip = machine(&rom);
+        schip.set_quirks(Quirks::super_chip());
+        schip.run_until(|m| m.cpu().pc() == 0x204).expect("Error running the machine");
+        assert_eq!(schip.frames(), 0);
+    }
+
+    #[test]
+    fn test_run_until_exit() {
+        // HIGH; LD V0, 0x01; EXIT
+        let rom = [0x00, 0xFF, 0x60, 0x01, 0x00, 0xFD];
+        let mut machine = Machine::for_variant(Variant::SuperChip, &rom).expect("Error loading ROM");
+        let cycles = machine.run_until(|_| false).expect("Error running the machine");
+        assert_eq!(cycles, 3);
+        assert!(machine.is_halted());
+        assert!(machine.display().is_hires());
+    }
+
+    #[test]
+    fn test_xo_chip() {
+        // LD I, long 0xF000; LD V0, 0xAB; LD [I], V0; PLANE 2; JP 0x20C
+        let rom = [0xF0, 0x00, 0xF0, 0x00, 0x60, 0xAB, 0xF0, 0x55, 0xF2, 0x01, 0x12, 0x0C];
+        let mut machine = Machine::for_variant(Variant::XoChip, &rom).expect("Error loading ROM");
+        assert_eq!(machine.ram().size(), 65536);
+        assert_eq!(machine.quirks(), Quirks::xo_chip());
+        machine.run_until(|m| m.cpu().pc() == 0x20C).expect("Error running the machine");
+        assert_eq!(machine.ram().read_u8(0xF000), Ok(0xAB));
+        assert_eq!(machine.cpu().i(), 0xF001);
+        assert_eq!(machine.display().planes(), 0b10);
+    }
+
+    #[test]
+    fn test_xo_chip_instruction_on_chip8() {
+        let mut machine = machine(&[0xF0, 0x00, 0xF0, 0x00]);
+        let result = machine.step();
+        assert_eq!(result, Err(CpuError::UnsupportedInstruction(Instruction::LoadLongIndex(0xF000))));
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut machine = machine(&[0x12, 0x04, 0x00, 0x00, 0x12, 0x08, 0x00, 0x00, 0x12, 0x00]);
+        let cycles = machine
+            .run_until(|m| m.cpu().pc() == 0x208)
+            .expect("Error running the machine");
+        assert_eq!(cycles, 2);
+    }
+
+    #[test]
+    fn test_watchpoint_log() {
+        // LD I, 0x300; LD V0, 0x11; LD V1, 0x22; LD [I], V1; LD I, 0x300; LD V3, [I]
+        let rom = [0xA3, 0x00, 0x60, 0x11, 0x61, 0x22, 0xF1, 0x55, 0xA3, 0x00, 0xF3, 0x65];
+        let mut machine = machine(&rom);
+        machine.ram_mut().add_watchpoint(Watchpoint { range: 0x301..0x302, access: Access::Write, action: WatchAction::Log });
+        machine.ram_mut().add_watchpoint(Watchpoint { range: 0x300..0x301, access: Access::Read, action: WatchAction::Log });
+        machine.run_cycles(6).expect("Error running the machine");
+        let events = machine.take_watch_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].pc, 0x206);
+        assert_eq!(events[0].instruction, Some(Instruction::StoreRegisters(1)));
+        assert_eq!(events[0].to_string(), "write 22 to 0301 by PC 0206 (LD [I], V1)");
+        assert_eq!(events[1].to_string(), "read 11 from 0300 by PC 020A (LD V3, [I])");
+        assert!(machine.take_watch_events().is_empty());
+        assert!(machine.watch_stop().is_none());
+    }
+
+    #[test]
+    fn test_watchpoint_stop() {
+        // LD I, 0x300; LD V0, 0x01; LD B, V0; JP 0x202
+        let mut machine = machine(&[0xA3, 0x00, 0x60, 0x01, 0xF0, 0x33, 0x12, 0x02]);
+        machine.ram_mut().add_watchpoint(Watchpoint { range: 0x302..0x303, access: Access::Write, action: WatchAction::Stop });
+        machine.ram_mut().add_watchpoint(Watchpoint { range: 0x206..0x207, access: Access::Execute, action: WatchAction::Log });
+        let cycles = machine.run_until(|_| false).expect("Error running the machine");
+        assert_eq!(cycles, 3);
+        let event = machine.take_watch_stop().expect("Watchpoint didn't stop");
+        assert_eq!(event.hit.value, 0x01);
+        assert_eq!(event.instruction, Some(Instruction::StoreBCD(0)));
+        assert!(machine.take_watch_events().is_empty());
+        machine.step().expect("Error running the machine");
+        assert_eq!(machine.take_watch_events()[0].hit.access, Access::Execute);
+    }
+
+    #[test]
+    fn test_tracer() {
+        // LD V0, 0x05; LD V1, 0x05; SE V0, V1; LD V2, 0x01; DRW V0, V1, 1; LD VF, K
+        let rom = [0x60, 0x05, 0x61, 0x05, 0x50, 0x10, 0x62, 0x01, 0xD0, 0x11, 0xFF, 0x0A];
+        let mut machine = machine(&rom);
+        machine.set_quirks(Quirks { display_wait: true, ..Quirks::cosmac_vip() });
+        let buffer = Buffer::default();
+        machine.set_tracer(Tracer::new(buffer.clone(), TraceFormat::Text));
+        machine.run_frame().expect("Error running the machine");
+        machine.run_cycles(3).expect("Error running the machine");
+        machine.take_tracer().expect("Missing tracer").finish().expect("Error finishing trace");
+
+        let output = buffer.contents();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines, [
+            "       0 0200: 6005       LD V0, 0x05          I=0000 SP=0 DT=00 ST=00 V0=05",
+            "       1 0202: 6105       LD V1, 0x05          I=0000 SP=0 DT=00 ST=00 V1=05",
+            "       2 0204: 5010       SE V0, V1            I=0000 SP=0 DT=00 ST=00",
+            "       3 0208: D011       DRW V0, V1, 1        I=0000 SP=0 DT=00 ST=00",
+            "      10 020A: FF0A       LD VF, K             I=0000 SP=0 DT=00 ST=00",
+        ]);
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        // RND V0, 0xFF; RND V1, 0x1F; LD F, V0; DRW V0, V1, 5; CALL 0x20C; JP 0x200; ADD V2, 1; RET
+        let rom = [
+            0xC0, 0xFF, 0xC1, 0x1F, 0xF0, 0x29, 0xD0, 0x15, 0x22, 0x0C, 0x12, 0x00, 0x72, 0x01, 0x00, 0xEE,
+        ];
+        let mut machine = Machine::with_seed(Ram::from_bytes(&rom).expect("Error loading ROM"), 7);
+        machine.run_cycles(25).expect("Error running the machine");
+        let saved = machine.save_state();
+        machine.run_cycles(40).expect("Error running the machine");
+        let expected = machine.save_state();
+
+        let mut restored = Machine::new(Ram::empty());
+        restored.load_state(&saved).expect("Error loading state");
+        assert_eq!(restored.cycles(), 25);
+        assert_eq!(restored.seed(), Some(7));
+        assert_eq!(restored.save_state(), saved);
+        restored.run_cycles(40).expect("Error running the machine");
+        assert_eq!(restored.save_state(), expected);
+        assert_eq!(restored.cpu().registers(), machine.cpu().registers());
+        assert_eq!(restored.display().pixels(), machine.display().pixels());
+    }
+
+    #[test]
+    fn test_load_state_errors() {
+        let mut machine = machine(&[0x12, 0x00]);
+        let saved = machine.save_state();
+        assert_eq!(machine.load_state(b"ROM!"), Err(StateError::NotASaveState));
+
+        let mut newer = saved.clone();
+        newer[4..6].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert_eq!(machine.load_state(&newer), Err(StateError::UnsupportedVersion { version: STATE_VERSION + 1 }));
+
+        assert_eq!(machine.load_state(&saved[..saved.len() - 1]), Err(StateError::Truncated));
+        let mut trailing = saved.clone();
+        trailing.push(0);
+        assert_eq!(machine.load_state(&trailing), Err(StateError::Corrupt("trailing data")));
+
+        let mut xo = Machine::for_variant(Variant::XoChip, &[0x12, 0x00]).expect("Error loading ROM");
+        assert_eq!(
+            xo.load_state(&saved),
+            Err(StateError::VariantMismatch { expected: Variant::XoChip, found: Variant::Chip8 })
+        );
+        xo.run_cycles(3).expect("Error running the machine");
+        assert_eq!(xo.cycles(), 3);
+
+        // An XO-CHIP state passed off as a CHIP-8 one
+        let mut relabelled = xo.save_state();
+        relabelled[6] = 0;
+        assert_eq!(
+            machine.load_state(&relabelled),
+            Err(StateError::VariantMismatch { expected: Variant::Chip8, found: Variant::XoChip })
+        );
+        xo.set_variant(Variant::Chip8);
+        let oversized = xo.save_state();
+        assert_eq!(machine.load_state(&oversized), Err(StateError::Corrupt("memory size doesn't match the variant")));
+        assert_eq!(machine.save_state(), saved);
+    }
+
+    #[test]
+    fn test_tracer_key_wait_and_error() {
+        // LD V5, K; RET
+        let mut machine = machine(&[0xF5, 0x0A, 0x00, 0xEE]);
+        let buffer = Buffer::default();
+        machine.set_tracer(Tracer::new(buffer.clone(), TraceFormat::Text));
+        machine.run_cycles(3).expect("Error running the machine");
+        machine.keypad_mut().press(0x7);
+        machine.step().expect("Error running the machine");
+        machine.keypad_mut().release(0x7);
+        machine.step().expect("Error running the machine");
+        assert_eq!(machine.step(), Err(CpuError::StackUnderflow));
+        machine.take_tracer().expect("Missing tracer").finish().expect("Error finishing trace");
+
+        let output = buffer.contents();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines, [
+            "       0 0200: F50A       LD V5, K             I=0000 SP=0 DT=00 ST=00",
+            "       4 0200: F50A       LD V5, K             I=0000 SP=0 DT=00 ST=00 V5=07",
+            "       5 0202: 00EE       RET                  I=0000 SP=0 DT=00 ST=00 error: stack underflow",
+        ]);
+    }
+}
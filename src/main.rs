@@ -1,7 +1,161 @@
-mod ram;
-mod cpu;
-mod instruction;
+use std::env;
+use std::fs;
+use std::process;
+use chip8_rust::machine::{Machine, TIMER_FREQUENCY};
+use chip8_rust::movie::{Movie, Player};
+use chip8_rust::quirks::Quirks;
+use chip8_rust::rng::SplitMix64;
+use chip8_rust::runner::{run, Limit, Report};
+use chip8_rust::variant::Variant;
+
+const USAGE: &str = "\
+usage: chip8-rust <rom> [options]
+  --variant <chip8|schip|xochip>   machine to emulate, chip8 by default
+  --quirks <vip|chip48|schip|xochip>
+                                   quirks profile, the variant's by default
+  --quirk <name>=<value>           turn a single quirk off or on with 0 or 1,
+                                   increment_index takes 0, x or x+1
+  --clock <hz>                     instructions per second
+  --seed <n>                       seed for RND
+  --cycles <n>                     stop after n steps of the CPU
+  --frames <n>                     stop after n 60 Hz frames, 3600 by default
+  --movie <file>                   play back recorded input, which also sets
+                                   the variant, quirks, clock and seed
+  --format <text|json>             report format, text by default";
+
+// A minute of emulated time when no limit is given
+const DEFAULT_FRAME_LIMIT: u64 = 3600;
+
+#[derive(Default)]
+struct Options {
+    rom: Option<String>,
+    variant: Option<Variant>,
+    quirks: Option<Quirks>,
+    overrides: Vec<(String, String)>,
+    clock: Option<u32>,
+    seed: Option<u64>,
+    limit: Option<Limit>,
+    movie: Option<String>,
+    json: bool,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn value(args: &mut impl Iterator<Item = String>, option: &str) -> String {
+    args.next().unwrap_or_else(|| usage_error(&format!("missing value for {}", option)))
+}
+
+fn number<T: std::str::FromStr>(text: &str, option: &str) -> T {
+    text.parse().unwrap_or_else(|_| usage_error(&format!("invalid number '{}' for {}", text, option)))
+}
+
+fn parse_options() -> Options {
+    let mut options = Options::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--variant" => match value(&mut args, &arg).parse() {
+                Ok(variant) => options.variant = Some(variant),
+                Err(error) => usage_error(&error.to_string()),
+            },
+            "--quirks" => {
+                let name = value(&mut args, &arg);
+                match Quirks::profile(&name) {
+                    Some(quirks) => options.quirks = Some(quirks),
+                    None => usage_error(&format!("unknown quirks profile '{}'", name)),
+                }
+            }
+            "--quirk" => {
+                let quirk = value(&mut args, &arg);
+                match quirk.split_once('=') {
+                    Some((name, value)) => options.overrides.push((name.to_string(), value.to_string())),
+                    None => usage_error(&format!("invalid quirk '{}', expected <name>=<value>", quirk)),
+                }
+            }
+            "--clock" => options.clock = Some(number(&value(&mut args, &arg), &arg)),
+            "--seed" => options.seed = Some(number(&value(&mut args, &arg), &arg)),
+            "--cycles" => options.limit = Some(Limit::Cycles(number(&value(&mut args, &arg), &arg))),
+            "--frames" => options.limit = Some(Limit::Frames(number(&value(&mut args, &arg), &arg))),
+            "--movie" => options.movie = Some(value(&mut args, &arg)),
+            "--format" => match value(&mut args, &arg).as_str() {
+                "text" => options.json = false,
+                "json" => options.json = true,
+                format => usage_error(&format!("unknown format '{}', expected text or json", format)),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if options.rom.is_none() && !arg.starts_with("--") => options.rom = Some(arg),
+            _ => usage_error(&format!("unexpected argument '{}'", arg)),
+        }
+    }
+    options
+}
+
+// Applies the command line settings, or takes them from the movie
+fn machine(options: &Options, rom: &[u8], movie: Option<&Movie>) -> Result<Machine, String> {
+    if let Some(movie) = movie {
+        let overridden = options.variant.is_some() || options.quirks.is_some() || !options.overrides.is_empty()
+            || options.clock.is_some() || options.seed.is_some();
+        if overridden {
+            return Err("the movie already sets the variant, quirks, clock and seed".to_string());
+        }
+        return movie.machine(rom).map_err(|error| error.to_string());
+    }
+
+    let variant = options.variant.unwrap_or_default();
+    let mut machine = Machine::for_variant(variant, rom).map_err(|error| error.to_string())?;
+    let mut quirks = options.quirks.unwrap_or_else(|| variant.quirks());
+    for (name, value) in &options.overrides {
+        if !quirks.set(name, value) {
+            return Err(format!("invalid quirk '{}={}'", name, value));
+        }
+    }
+    machine.set_quirks(quirks);
+    if let Some(clock) = options.clock {
+        machine.set_instructions_per_frame((clock / TIMER_FREQUENCY).max(1));
+    }
+    if let Some(seed) = options.seed {
+        machine.set_rng(Box::new(SplitMix64::new(seed)));
+    }
+    Ok(machine)
+}
 
 fn main() {
-    println!("Hello World!");
+    let options = parse_options();
+    let path = options.rom.clone().unwrap_or_else(|| usage_error("missing ROM"));
+    let rom = fs::read(&path).unwrap_or_else(|error| {
+        eprintln!("{}: {}", path, error);
+        process::exit(1);
+    });
+    let movie = options.movie.as_ref().map(|movie_path| {
+        Movie::load(movie_path).unwrap_or_else(|error| {
+            eprintln!("{}: {}", movie_path, error);
+            process::exit(1);
+        })
+    });
+    let mut machine = machine(&options, &rom, movie.as_ref()).unwrap_or_else(|error| {
+        eprintln!("{}: {}", path, error);
+        process::exit(1);
+    });
+
+    // Long enough for the whole movie to play unless told otherwise
+    let last_input = movie.as_ref().map_or(0, |movie| movie.last_frame() + 1);
+    let limit = options.limit.unwrap_or(Limit::Frames(DEFAULT_FRAME_LIMIT.max(last_input)));
+    let mut player = movie.map(Player::new);
+    let exit = run(&mut machine, player.as_mut(), limit);
+
+    let report = Report::new(&machine, exit);
+    if options.json {
+        println!("{}", report.to_json());
+    } else {
+        println!("{}", report.to_text());
+    }
+    if report.is_error() {
+        process::exit(1);
+    }
 }
@@ -0,0 +1,406 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use crate::cpu::CpuError;
+use crate::keypad::KEY_COUNT;
+use crate::machine::{Machine, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use crate::quirks::Quirks;
+use crate::ram::LoadError;
+use crate::rng::{SplitMix64, DEFAULT_SEED};
+use crate::variant::Variant;
+
+// First line of a movie file, followed by the format version
+pub const MOVIE_MAGIC: &str = "chip8-movie";
+pub const MOVIE_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    NotAMovie,
+    UnsupportedVersion { version: u32 },
+    Parse { line: usize, message: String },
+    Io(io::Error),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie file"),
+            MovieError::UnsupportedVersion { version } => {
+                write!(f, "movie version {} is not supported, expected {}", version, MOVIE_VERSION)
+            }
+            MovieError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            MovieError::Io(err) => write!(f, "error accessing movie: {}", err),
+        }
+    }
+}
+
+impl Error for MovieError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MovieError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> Self {
+        MovieError::Io(err)
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum KeyAction {
+    Press,
+    Release,
+}
+
+// A key going down or up at the start of a frame
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub action: KeyAction,
+}
+
+impl fmt::Display for InputEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            KeyAction::Press => "press",
+            KeyAction::Release => "release",
+        };
+        write!(f, "{} {} {:X}", self.frame, action, self.key)
+    }
+}
+
+// Everything needed to replay a run of a ROM: the machine settings and the
+// keypad input, frame by frame. Stored as text so bug reports can be read
+// and edited by hand:
+//
+//   chip8-movie 1
+//   variant schip
+//   seed 42
+//   instructions-per-frame 10
+//   quirks shift_uses_vy=0 jump_uses_vx=1 increment_index=x ...
+//   120 press 5
+//   135 release 5
+#[derive(PartialEq, Clone, Debug)]
+pub struct Movie {
+    variant: Variant,
+    quirks: Quirks,
+    seed: u64,
+    instructions_per_frame: u32,
+    events: Vec<InputEvent>,
+}
+
+impl Movie {
+    pub fn new(variant: Variant, quirks: Quirks, seed: u64, instructions_per_frame: u32) -> Self {
+        Movie { variant, quirks, seed, instructions_per_frame, events: Vec::new() }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MovieError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        Ok(fs::write(path, self.to_string())?)
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events
+    }
+
+    // Frame of the last input, after which the movie has nothing left to do
+    pub fn last_frame(&self) -> u64 {
+        self.events.last().map_or(0, |event| event.frame)
+    }
+
+    // Events have to be added in frame order
+    pub fn push(&mut self, event: InputEvent) {
+        assert!(event.frame >= self.last_frame(), "Input events must be in frame order");
+        assert!((event.key as usize) < KEY_COUNT, "Invalid key {:X}", event.key);
+        self.events.push(event);
+    }
+
+    // Machine with the movie's settings and the ROM loaded, ready to play it
+    pub fn machine(&self, rom: &[u8]) -> Result<Machine, LoadError> {
+        let mut machine = Machine::for_variant(self.variant, rom)?;
+        machine.set_quirks(self.quirks);
+        machine.set_rng(Box::new(SplitMix64::new(self.seed)));
+        machine.set_instructions_per_frame(self.instructions_per_frame);
+        Ok(machine)
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} {}", MOVIE_MAGIC, MOVIE_VERSION)?;
+        writeln!(f, "variant {}", self.variant)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "instructions-per-frame {}", self.instructions_per_frame)?;
+        write!(f, "quirks")?;
+        for (name, value) in self.quirks.values() {
+            write!(f, " {}={}", name, value)?;
+        }
+        writeln!(f)?;
+        for event in &self.events {
+            writeln!(f, "{}", event)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_error(line: usize, message: String) -> MovieError {
+    MovieError::Parse { line, message }
+}
+
+fn parse_number<T: FromStr>(line: usize, text: &str) -> Result<T, MovieError> {
+    text.parse().map_err(|_| parse_error(line, format!("invalid number '{}'", text)))
+}
+
+// Lines starting with # are comments. Settings left out take the defaults
+// of the variant.
+impl FromStr for Movie {
+    type Err = MovieError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut lines = text.lines().enumerate().map(|(index, line)| (index + 1, line.trim()));
+        let version = match lines.next().map(|(_, line)| line.split_whitespace().collect::<Vec<_>>()) {
+            Some(words) if words.len() == 2 && words[0] == MOVIE_MAGIC => {
+                words[1].parse().map_err(|_| MovieError::NotAMovie)?
+            }
+            _ => return Err(MovieError::NotAMovie),
+        };
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion { version });
+        }
+
+        let mut variant = Variant::default();
+        let mut quirks = None;
+        let mut seed = DEFAULT_SEED;
+        let mut instructions_per_frame = DEFAULT_INSTRUCTIONS_PER_FRAME;
+        let mut events: Vec<InputEvent> = Vec::new();
+        for (number, line) in lines.filter(|(_, line)| !line.is_empty() && !line.starts_with('#')) {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                ["variant", name] => {
+                    variant = name.parse().map_err(|err| parse_error(number, format!("{}", err)))?;
+                }
+                ["seed", value] => seed = parse_number(number, value)?,
+                ["instructions-per-frame", value] => {
+                    instructions_per_frame = parse_number(number, value)?;
+                    if instructions_per_frame == 0 {
+                        return Err(parse_error(number, "a frame needs at least one instruction".to_string()));
+                    }
+                }
+                ["quirks", ref flags @ ..] => {
+                    let mut parsed = quirks.unwrap_or_else(|| variant.quirks());
+                    for flag in flags {
+                        let known = match flag.split_once('=') {
+                            Some((name, value)) => parsed.set(name, value),
+                            None => false,
+                        };
+                        if !known {
+                            return Err(parse_error(number, format!("invalid quirk '{}'", flag)));
+                        }
+                    }
+                    quirks = Some(parsed);
+                }
+                [frame, action @ ("press" | "release"), key] => {
+                    let frame = parse_number(number, frame)?;
+                    if events.last().is_some_and(|last| frame < last.frame) {
+                        return Err(parse_error(number, "input events must be in frame order".to_string()));
+                    }
+                    let key = match u8::from_str_radix(key, 16) {
+                        Ok(key) if (key as usize) < KEY_COUNT => key,
+                        _ => return Err(parse_error(number, format!("invalid key '{}'", key))),
+                    };
+                    let action = if action == "press" { KeyAction::Press } else { KeyAction::Release };
+                    events.push(InputEvent { frame, key, action });
+                }
+                _ => return Err(parse_error(number, format!("unexpected line '{}'", line))),
+            }
+        }
+        let quirks = quirks.unwrap_or_else(|| variant.quirks());
+        Ok(Movie { variant, quirks, seed, instructions_per_frame, events })
+    }
+}
+
+// Builds a movie by watching the keypad of a machine as it runs
+pub struct Recorder {
+    movie: Movie,
+    keys: [bool; KEY_COUNT],
+}
+
+impl Recorder {
+    // Movies play back from a reset machine, so this returns None unless the
+    // machine hasn't run yet and its random source can be reproduced
+    pub fn new(machine: &Machine) -> Option<Self> {
+        if machine.cycles() != 0 || machine.frames() != 0 {
+            return None;
+        }
+        let seed = machine.seed()?;
+        let movie = Movie::new(machine.variant(), machine.quirks(), seed, machine.instructions_per_frame());
+        Some(Recorder { movie, keys: [false; KEY_COUNT] })
+    }
+
+    // Records the keys that changed since the last capture. Meant to be
+    // called between frames, after updating the keypad, as playback applies
+    // the input at the start of a frame.
+    pub fn capture(&mut self, machine: &Machine) {
+        let keys = *machine.keypad().keys();
+        for (key, (&pressed, &was_pressed)) in keys.iter().zip(&self.keys).enumerate() {
+            if pressed != was_pressed {
+                let action = if pressed { KeyAction::Press } else { KeyAction::Release };
+                self.movie.push(InputEvent { frame: machine.frames(), key: key as u8, action });
+            }
+        }
+        self.keys = keys;
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+// Drives the keypad of a machine from a movie
+pub struct Player {
+    movie: Movie,
+    next: usize,
+}
+
+impl Player {
+    pub fn new(movie: Movie) -> Self {
+        Player { movie, next: 0 }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next == self.movie.events.len()
+    }
+
+    // Presses and releases the keys of every event due by the machine's
+    // current frame
+    pub fn apply(&mut self, machine: &mut Machine) {
+        while let Some(event) = self.movie.events.get(self.next) {
+            if event.frame > machine.frames() {
+                break;
+            }
+            match event.action {
+                KeyAction::Press => machine.keypad_mut().press(event.key),
+                KeyAction::Release => machine.keypad_mut().release(event.key),
+            }
+            self.next += 1;
+        }
+    }
+
+    pub fn play_frame(&mut self, machine: &mut Machine) -> Result<(), CpuError> {
+        self.apply(machine);
+        machine.run_frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InputEvent, KeyAction, Movie, MovieError, Player, Recorder, MOVIE_VERSION};
+    use crate::machine::Machine;
+    use crate::quirks::Quirks;
+    use crate::ram::Ram;
+    use crate::variant::Variant;
+
+    // RND V0, 0xFF; SKP V1; JP 0x200; ADD V2, 1; JP 0x200
+    const ROM: [u8; 10] = [0xC0, 0xFF, 0xE1, 0x9E, 0x12, 0x00, 0x72, 0x01, 0x12, 0x00];
+
+    #[test]
+    fn test_text_round_trip() {
+        let mut movie = Movie::new(Variant::SuperChip, Quirks::chip48(), 1234, 15);
+        movie.push(InputEvent { frame: 3, key: 0xA, action: KeyAction::Press });
+        movie.push(InputEvent { frame: 7, key: 0xA, action: KeyAction::Release });
+        let text = movie.to_string();
+        assert!(text.starts_with("chip8-movie 1\nvariant schip\nseed 1234\ninstructions-per-frame 15\nquirks "));
+        assert!(text.ends_with("\n3 press A\n7 release A\n"));
+        // CHIP-48 settings on a SUPER-CHIP machine survive the trip
+        assert!(text.contains(" increment_index=x "));
+        assert_eq!(text.parse::<Movie>().expect("Error parsing movie"), movie);
+    }
+
+    #[test]
+    fn test_parse_defaults_and_errors() {
+        let movie: Movie = "chip8-movie 1\n# no settings\nvariant xochip\n\n0 press 1\n".parse().expect("Error parsing movie");
+        assert_eq!(movie.quirks(), Quirks::xo_chip());
+        assert_eq!(movie.last_frame(), 0);
+
+        assert!(matches!("ROM".parse::<Movie>(), Err(MovieError::NotAMovie)));
+        assert!(matches!(
+            format!("chip8-movie {}", MOVIE_VERSION + 1).parse::<Movie>(),
+            Err(MovieError::UnsupportedVersion { .. })
+        ));
+        for (text, line) in [
+            ("chip8-movie 1\nquirks wrap=1", 2),
+            ("chip8-movie 1\n5 press 1\n4 release 1", 3),
+            ("chip8-movie 1\n\n1 press G", 3),
+            ("chip8-movie 1\nseed -1", 2),
+        ] {
+            match text.parse::<Movie>() {
+                Err(MovieError::Parse { line: error_line, .. }) => assert_eq!(error_line, line, "{}", text),
+                other => panic!("Expected a parse error for {:?}, got {:?}", text, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_and_play_back() {
+        let mut machine = Machine::with_seed(Ram::from_bytes(&ROM).expect("Error loading ROM"), 99);
+        let mut recorder = Recorder::new(&machine).expect("Machine isn't reproducible");
+        let mut running = Machine::new(Ram::from_bytes(&ROM).expect("Error loading ROM"));
+        running.run_frame().expect("Error running the machine");
+        assert!(Recorder::new(&running).is_none());
+        for frame in 0..30 {
+            match frame {
+                5 | 20 => machine.keypad_mut().press(0),
+                8 | 22 => machine.keypad_mut().release(0),
+                _ => (),
+            }
+            recorder.capture(&machine);
+            machine.run_frame().expect("Error running the machine");
+        }
+        let movie = recorder.finish();
+        assert_eq!(movie.events().len(), 4);
+        assert!(machine.cpu().registers()[2] > 0);
+
+        let movie: Movie = movie.to_string().parse().expect("Error parsing movie");
+        let mut replay = movie.machine(&ROM).expect("Error loading ROM");
+        let mut player = Player::new(movie);
+        for _ in 0..30 {
+            player.play_frame(&mut replay).expect("Error running the machine");
+        }
+        assert!(player.is_finished());
+        assert_eq!(replay.save_state(), machine.save_state());
+    }
+}
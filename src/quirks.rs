@@ -0,0 +1,195 @@
+use crate::variant::normalize_name;
+
+// What Fx55/Fx65 do to I once the registers are transferred
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum IndexIncrement {
+    // SUPER-CHIP 1.1 leaves I alone
+    None,
+    // CHIP-48 leaves I pointing at the last register transferred
+    ByX,
+    // The VIP leaves I pointing past the last register transferred
+    ByXPlusOne,
+}
+
+impl IndexIncrement {
+    pub fn amount(self, x: u8) -> u16 {
+        match self {
+            IndexIncrement::None => 0,
+            IndexIncrement::ByX => x as u16,
+            IndexIncrement::ByXPlusOne => x as u16 + 1,
+        }
+    }
+}
+
+// Behaviours that differ between CHIP-8 interpreters. Games written for one
+// interpreter often misbehave on another, so these are picked per ROM.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Quirks {
+    // 8xy6/8xyE shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    // Bnnn jumps to xnn + Vx instead of nnn + V0
+    pub jump_uses_vx: bool,
+    // 8xy1/8xy2/8xy3 reset VF to 0
+    pub logic_resets_vf: bool,
+    // How far Fx55/Fx65 move I after the transfer
+    pub increment_index: IndexIncrement,
+    // Fx1E sets VF when I goes past 0xFFF
+    pub index_overflow_sets_vf: bool,
+    // Sprites are cut off at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    // Dxyn waits for the next 60 Hz frame before execution continues
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    pub fn cosmac_vip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            increment_index: IndexIncrement::ByXPlusOne,
+            index_overflow_sets_vf: false,
+            clip_sprites: true,
+            display_wait: true,
+        }
+    }
+
+    pub fn chip48() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            increment_index: IndexIncrement::ByX,
+            index_overflow_sets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    pub fn super_chip() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            increment_index: IndexIncrement::None,
+            index_overflow_sets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    pub fn xo_chip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            increment_index: IndexIncrement::ByXPlusOne,
+            index_overflow_sets_vf: false,
+            clip_sprites: false,
+            display_wait: false,
+        }
+    }
+
+    // Profile by name, as used on the command line and in movie files
+    pub fn profile(name: &str) -> Option<Self> {
+        match normalize_name(name).as_str() {
+            "vip" | "cosmac" | "cosmacvip" | "chip8" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" | "superchip" => Some(Quirks::super_chip()),
+            "xo" | "xochip" => Some(Quirks::xo_chip()),
+            _ => None,
+        }
+    }
+
+    // Every quirk by field name, in declaration order, with its value as
+    // written in movie files and on the command line
+    pub fn values(&self) -> [(&'static str, &'static str); 7] {
+        let flag = |value: bool| if value { "1" } else { "0" };
+        let increment = match self.increment_index {
+            IndexIncrement::None => "0",
+            IndexIncrement::ByX => "x",
+            IndexIncrement::ByXPlusOne => "x+1",
+        };
+        [
+            ("shift_uses_vy", flag(self.shift_uses_vy)),
+            ("jump_uses_vx", flag(self.jump_uses_vx)),
+            ("logic_resets_vf", flag(self.logic_resets_vf)),
+            ("increment_index", increment),
+            ("index_overflow_sets_vf", flag(self.index_overflow_sets_vf)),
+            ("clip_sprites", flag(self.clip_sprites)),
+            ("display_wait", flag(self.display_wait)),
+        ]
+    }
+
+    // Flags take 0 or 1, increment_index takes 0, x or x+1. Returns false
+    // if there's no quirk with that name or the value doesn't fit it.
+    pub fn set(&mut self, name: &str, value: &str) -> bool {
+        if name == "increment_index" {
+            self.increment_index = match value {
+                "0" => IndexIncrement::None,
+                "x" => IndexIncrement::ByX,
+                "x+1" => IndexIncrement::ByXPlusOne,
+                _ => return false,
+            };
+            return true;
+        }
+        let flag = match name {
+            "shift_uses_vy" => &mut self.shift_uses_vy,
+            "jump_uses_vx" => &mut self.jump_uses_vx,
+            "logic_resets_vf" => &mut self.logic_resets_vf,
+            "index_overflow_sets_vf" => &mut self.index_overflow_sets_vf,
+            "clip_sprites" => &mut self.clip_sprites,
+            "display_wait" => &mut self.display_wait,
+            _ => return false,
+        };
+        *flag = match value {
+            "0" => false,
+            "1" => true,
+            _ => return false,
+        };
+        true
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::cosmac_vip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IndexIncrement, Quirks};
+
+    #[test]
+    fn test_index_increment() {
+        assert_eq!(Quirks::cosmac_vip().increment_index.amount(3), 4);
+        assert_eq!(Quirks::chip48().increment_index.amount(3), 3);
+        assert_eq!(Quirks::super_chip().increment_index.amount(3), 0);
+        assert_ne!(Quirks::chip48(), Quirks::super_chip());
+    }
+
+    #[test]
+    fn test_profile_names() {
+        for name in ["xo", "xochip", "XO-CHIP", "xo_chip"] {
+            assert_eq!(Quirks::profile(name), Some(Quirks::xo_chip()), "{}", name);
+        }
+        assert_eq!(Quirks::profile("Super-Chip"), Some(Quirks::super_chip()));
+        assert_eq!(Quirks::profile("chip-48"), Some(Quirks::chip48()));
+        assert_eq!(Quirks::profile("eti660"), None);
+    }
+
+    #[test]
+    fn test_values_and_set() {
+        let mut quirks = Quirks::super_chip();
+        for (name, value) in Quirks::chip48().values() {
+            assert!(quirks.set(name, value), "{}={}", name, value);
+        }
+        assert_eq!(quirks, Quirks::chip48());
+        assert!(quirks.set("increment_index", "x+1"));
+        assert_eq!(quirks.increment_index, IndexIncrement::ByXPlusOne);
+        assert!(!quirks.set("increment_index", "1"));
+        assert!(!quirks.set("clip_sprites", "x"));
+        assert!(!quirks.set("wrap", "1"));
+    }
+}
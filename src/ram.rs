@@ -1,55 +1,629 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::Path;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::font::{BIG_FONT, BIG_FONT_ADDRESS, BIG_FONT_SIZE, FONT, FONT_ADDRESS, FONT_SIZE};
 
-const RAM_SIZE: usize = 4096;
+pub const RAM_SIZE: usize = 4096;
+// XO-CHIP can address a full 64 KiB
+pub const XO_RAM_SIZE: usize = 65536;
+pub const PROGRAM_START: u16 = 0x200;
+// ETI-660 programs start higher up in memory
+pub const ETI_660_PROGRAM_START: u16 = 0x600;
+
+#[derive(Debug)]
+pub enum LoadError {
+    TooLarge { size: usize, capacity: usize },
+    Empty,
+    Io(io::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::TooLarge { size, capacity } => {
+                write!(f, "ROM too large for {} bytes ({} bytes)", capacity, size)
+            }
+            LoadError::Empty => write!(f, "empty ROM"),
+            LoadError::Io(err) => write!(f, "error reading ROM: {}", err),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum MemoryError {
+    OutOfBounds { address: usize },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryError::OutOfBounds { address } => {
+                write!(f, "memory access out of bounds at {:04X}", address)
+            }
+        }
+    }
+}
+
+impl Error for MemoryError {}
+
+// What happens to accesses past the end of memory
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum AddressPolicy {
+    // Mask the address to the memory size, 12 bits for 4 KiB and 16 for 64 KiB
+    Wrap,
+    // Fail with MemoryError::OutOfBounds
+    Error,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    // Fetching an instruction that starts in the range
+    Execute,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum WatchAction {
+    Stop,
+    Log,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Watchpoint {
+    pub range: Range<usize>,
+    pub access: Access,
+    pub action: WatchAction,
+}
+
+// A watched access, the value is the byte read or written or the opcode fetched
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Hit {
+    pub access: Access,
+    pub address: u16,
+    pub value: u16,
+    pub action: WatchAction,
+}
 
 pub struct Ram {
-    memory: [u8; RAM_SIZE]
+    memory: Vec<u8>,
+    font_address: u16,
+    program_start: u16,
+    policy: AddressPolicy,
+    watchpoints: Vec<Watchpoint>,
+    // Reads only borrow the memory, so hits are collected behind a RefCell
+    hits: RefCell<Vec<Hit>>,
 }
 
 impl Ram {
-    pub fn new(filename: &str) -> Self {
-        let mut f = File::open(filename).expect("Error opening the file");
-        let mut buffer = [0u8; RAM_SIZE];
-        let _bytes_read = f.read(&mut buffer[0x200..]).expect("Error reading the file");
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        Ram::from_reader(File::open(path)?)
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, LoadError> {
+        let mut rom = Vec::new();
+        reader.read_to_end(&mut rom)?;
+        Ram::from_bytes(&rom)
+    }
+
+    pub fn from_bytes(rom: &[u8]) -> Result<Self, LoadError> {
+        let mut ram = Ram::empty();
+        ram.load_rom(rom)?;
+        Ok(ram)
+    }
+
+    // Memory with the built-in font and no program loaded
+    pub fn empty() -> Self {
+        Ram::with_font(&FONT, FONT_ADDRESS)
+    }
+
+    // Memory of a larger size, such as XO_RAM_SIZE, with the built-in font
+    pub fn with_size(size: usize) -> Self {
+        Ram::build(size, &FONT, FONT_ADDRESS)
+    }
+
+    // Memory with a custom font set loaded at the given address. The large
+    // SUPER-CHIP font always sits at BIG_FONT_ADDRESS.
+    pub fn with_font(font: &[u8; FONT_SIZE], address: u16) -> Self {
+        Ram::build(RAM_SIZE, font, address)
+    }
+
+    fn build(size: usize, font: &[u8; FONT_SIZE], address: u16) -> Self {
+        assert!(
+            size.is_power_of_two() && (RAM_SIZE..=XO_RAM_SIZE).contains(&size),
+            "Memory size must be a power of two between 4 KiB and 64 KiB"
+        );
+        let start = address as usize;
+        let big_start = BIG_FONT_ADDRESS as usize;
+        assert!(start + FONT_SIZE <= PROGRAM_START as usize, "The font must fit below the program area");
+        assert!(
+            start + FONT_SIZE <= big_start || start >= big_start + BIG_FONT_SIZE,
+            "The font must not overlap the large font"
+        );
+        let mut memory = vec![0; size];
+        memory[big_start..big_start + BIG_FONT_SIZE].copy_from_slice(&BIG_FONT);
+        memory[start..start + FONT_SIZE].copy_from_slice(font);
         Ram {
-            memory: buffer
+            memory,
+            font_address: address,
+            program_start: PROGRAM_START,
+            policy: AddressPolicy::Error,
+            watchpoints: Vec::new(),
+            hits: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn address_policy(&self) -> AddressPolicy {
+        self.policy
+    }
+
+    pub fn set_address_policy(&mut self, policy: AddressPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn font_address(&self) -> u16 {
+        self.font_address
+    }
+
+    pub fn big_font_address(&self) -> u16 {
+        BIG_FONT_ADDRESS
+    }
+
+    // Address the last ROM was loaded at, where execution should begin
+    pub fn program_start(&self) -> u16 {
+        self.program_start
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), LoadError> {
+        self.load_rom_at(rom, PROGRAM_START)
+    }
+
+    pub fn load_rom_at(&mut self, rom: &[u8], address: u16) -> Result<(), LoadError> {
+        if rom.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        let capacity = self.memory.len().saturating_sub(address as usize);
+        if rom.len() > capacity {
+            return Err(LoadError::TooLarge { size: rom.len(), capacity });
         }
+        let start = address as usize;
+        self.memory[start..start + rom.len()].copy_from_slice(rom);
+        self.program_start = address;
+        Ok(())
     }
 
-    pub fn word(&self, offset: u16) -> u16 {
+    pub fn read_u8(&self, address: u16) -> Result<u8, MemoryError> {
+        let address = self.resolve(address as usize)?;
+        self.watch(Access::Read, address, self.memory[address] as u16);
+        Ok(self.memory[address])
+    }
+
+    pub fn write_u8(&mut self, address: u16, value: u8) -> Result<(), MemoryError> {
+        let address = self.resolve(address as usize)?;
+        self.watch(Access::Write, address, value as u16);
+        self.memory[address] = value;
+        Ok(())
+    }
+
+    pub fn read_word(&self, address: u16) -> Result<u16, MemoryError> {
         // Instructions are 2 bytes long and stored in big-endian format.
         // MSB -> Most significant byte first
-        let higher = (self.memory[offset as usize] as u16) << 8;
-        let lower = self.memory[(offset + 1) as usize] as u16;
-        higher & lower
+        let higher = self.memory[self.resolve(address as usize)?] as u16;
+        let lower = self.memory[self.resolve(address as usize + 1)?] as u16;
+        Ok(higher << 8 | lower)
+    }
+
+    // Reads the opcode of the instruction about to run, which is what
+    // execute watchpoints see. Plain reads of words are not watched.
+    pub fn fetch_word(&self, address: u16) -> Result<u16, MemoryError> {
+        let opcode = self.read_word(address)?;
+        self.watch(Access::Execute, self.resolve(address as usize)?, opcode);
+        Ok(opcode)
+    }
+
+    // Slices are contiguous, so they never wrap even under AddressPolicy::Wrap.
+    // Only the start address is wrapped.
+    pub fn read_slice(&self, address: u16, len: usize) -> Result<&[u8], MemoryError> {
+        let start = self.resolve(address as usize)?;
+        let end = start + len;
+        if end > self.memory.len() {
+            return Err(MemoryError::OutOfBounds { address: self.memory.len() });
+        }
+        for address in start..end {
+            self.watch(Access::Read, address, self.memory[address] as u16);
+        }
+        Ok(&self.memory[start..end])
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
     }
 
-    pub fn print(&self) {
-        for (idx, val) in self.memory.windows(16).enumerate() {
-            print!("{:04x}: ", idx*16);
-            for b in val {
-                print!("{b:02x} ");
+    // Removes the watchpoints covering the address, returning how many
+    pub fn remove_watchpoints(&mut self, address: u16) -> usize {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| !watchpoint.range.contains(&(address as usize)));
+        count - self.watchpoints.len()
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    // Hits since the last call, oldest first
+    pub fn take_hits(&self) -> Vec<Hit> {
+        self.hits.take()
+    }
+
+    // Watchpoints are a debugging aid and aren't part of the state
+    pub(crate) fn write_state(&self, state: &mut StateWriter) {
+        state.u32(self.memory.len() as u32);
+        state.u16(self.font_address);
+        state.u16(self.program_start);
+        state.bool(self.policy == AddressPolicy::Wrap);
+        state.bytes(&self.memory);
+    }
+
+    pub(crate) fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let size = state.u32()? as usize;
+        if !size.is_power_of_two() || !(RAM_SIZE..=XO_RAM_SIZE).contains(&size) {
+            return Err(StateError::Corrupt("invalid memory size"));
+        }
+        // Same layout rules as `build`: below the program area and clear of
+        // the large font
+        let font_address = state.u16()?;
+        let start = font_address as usize;
+        let big_start = BIG_FONT_ADDRESS as usize;
+        if start + FONT_SIZE > PROGRAM_START as usize
+            || (start + FONT_SIZE > big_start && start < big_start + BIG_FONT_SIZE)
+        {
+            return Err(StateError::Corrupt("invalid font address"));
+        }
+        let program_start = state.u16()?;
+        if program_start as usize >= size {
+            return Err(StateError::Corrupt("invalid program start"));
+        }
+        let policy = if state.bool()? { AddressPolicy::Wrap } else { AddressPolicy::Error };
+        let memory = state.bytes(size)?.to_vec();
+        Ok(Ram {
+            memory,
+            font_address,
+            program_start,
+            policy,
+            watchpoints: Vec::new(),
+            hits: RefCell::new(Vec::new()),
+        })
+    }
+
+    fn watch(&self, access: Access, address: usize, value: u16) {
+        for watchpoint in &self.watchpoints {
+            if watchpoint.access == access && watchpoint.range.contains(&address) {
+                self.hits.borrow_mut().push(Hit { access, address: address as u16, value, action: watchpoint.action });
             }
-            println!();
         }
     }
+
+    fn resolve(&self, address: usize) -> Result<usize, MemoryError> {
+        if address < self.memory.len() {
+            return Ok(address);
+        }
+        match self.policy {
+            AddressPolicy::Wrap => Ok(address & (self.memory.len() - 1)),
+            AddressPolicy::Error => Err(MemoryError::OutOfBounds { address }),
+        }
+    }
+
+    // Classic hex dump, 16 bytes a line with their printable characters
+    pub fn hex_dump(&self, range: Range<usize>) -> String {
+        let end = range.end.min(self.memory.len());
+        let mut dump = String::new();
+        let mut address = range.start;
+        while address < end {
+            let row = &self.memory[address..end.min(address + 16)];
+            let hex: Vec<String> = row.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let text: String = row.iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            dump.push_str(&format!("{:04X}: {:<47}  |{}|\n", address, hex.join(" "), text));
+            address += 16;
+        }
+        dump
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Ram;
+    use std::cell::RefCell;
+    use super::{Access, AddressPolicy, Hit, LoadError, MemoryError, Ram, WatchAction, Watchpoint};
+    use crate::font::{BIG_FONT, FONT, FONT_ADDRESS};
+    use crate::state::{StateError, StateReader, StateWriter};
 
     #[test]
     fn test_load_rom() {
-        let mut ram = Ram { memory: [0; 4096] };
+        let mut ram = Ram {
+            memory: vec![0; 4096],
+            font_address: FONT_ADDRESS,
+            program_start: 0x200,
+            policy: AddressPolicy::Error,
+            watchpoints: Vec::new(),
+            hits: RefCell::new(Vec::new()),
+        };
         let mut rom: [u8; 3584] = [0; 3584];
         rom[0] = 0xFF;
         rom[1] = 0xCC;
 
-        // ram.load_rom(&rom);
-        // assert_eq!(ram.memory[0x200], 0xFF);
-        // assert_eq!(ram.memory[0x201], 0xCC);
-        assert_eq!(1, 1);
+        ram.load_rom(&rom).expect("Error loading ROM");
+        assert_eq!(ram.memory[0x200], 0xFF);
+        assert_eq!(ram.memory[0x201], 0xCC);
+    }
+
+    #[test]
+    fn test_load_rom_too_large() {
+        let mut ram = Ram::empty();
+        let rom: [u8; 3585] = [0xAA; 3585];
+        let err = ram.load_rom(&rom).unwrap_err();
+        assert!(matches!(err, LoadError::TooLarge { size: 3585, capacity: 3584 }));
+        assert_eq!(err.to_string(), "ROM too large for 3584 bytes (3585 bytes)");
+        assert_eq!(ram.memory[0x200], 0);
+    }
+
+    #[test]
+    fn test_load_rom_empty() {
+        let mut ram = Ram::empty();
+        let err = ram.load_rom(&[]).unwrap_err();
+        assert!(matches!(err, LoadError::Empty));
+        assert_eq!(err.to_string(), "empty ROM");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_load_rom_at() {
+        let mut ram = Ram::empty();
+        ram.load_rom_at(&[0x12, 0x34], 0x600).expect("Error loading ROM");
+        assert_eq!(ram.memory[0x600], 0x12);
+        assert_eq!(ram.memory[0x601], 0x34);
+        assert_eq!(ram.program_start(), 0x600);
+
+        let rom = [0; 2561];
+        let err = ram.load_rom_at(&rom, 0x600).unwrap_err();
+        assert!(matches!(err, LoadError::TooLarge { size: 2561, capacity: 2560 }));
+    }
+
+    #[test]
+    fn test_with_size() {
+        let mut ram = Ram::with_size(65536);
+        assert_eq!(ram.size(), 65536);
+        assert_eq!(ram.memory[0x050..0x0A0], FONT);
+        let rom = vec![0xAA; 65536 - 0x200];
+        ram.load_rom(&rom).expect("Error loading ROM");
+        assert_eq!(ram.read_u8(0xFFFF), Ok(0xAA));
+        assert_eq!(ram.read_word(0xFFFF), Err(MemoryError::OutOfBounds { address: 0x10000 }));
+        ram.set_address_policy(AddressPolicy::Wrap);
+        assert_eq!(ram.read_word(0xFFFF), Ok(0xAA00));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_with_size_not_power_of_two() {
+        Ram::with_size(5000);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let ram = Ram::from_bytes(&[0xA2, 0x2A]).expect("Error loading ROM");
+        assert_eq!(ram.memory[0x200], 0xA2);
+        assert_eq!(ram.program_start(), 0x200);
+        assert_eq!(ram.memory[0x050..0x0A0], FONT);
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let rom: &[u8] = &[0x00, 0xE0, 0x12, 0x00];
+        let ram = Ram::from_reader(rom).expect("Error loading ROM");
+        assert_eq!(ram.memory[0x200..0x204], [0x00, 0xE0, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn test_from_file_missing() {
+        let err = Ram::from_file("does/not/exist.ch8").err().expect("Loading should fail");
+        assert!(matches!(err, LoadError::Io(_)));
+    }
+
+    #[test]
+    fn test_empty_loads_font() {
+        let ram = Ram::empty();
+        assert_eq!(ram.font_address(), 0x050);
+        assert_eq!(ram.memory[0x050..0x0A0], FONT);
+        assert_eq!(ram.big_font_address(), 0x0A0);
+        assert_eq!(ram.memory[0x0A0..0x140], BIG_FONT);
+        assert_eq!(ram.memory[0x200], 0);
+    }
+
+    #[test]
+    fn test_with_font() {
+        let font = [0xAA; 80];
+        let ram = Ram::with_font(&font, 0x000);
+        assert_eq!(ram.font_address(), 0x000);
+        assert_eq!(ram.memory[0x000..0x050], font);
+        assert_eq!(ram.memory[0x050], 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_with_font_overlapping_big_font() {
+        Ram::with_font(&FONT, 0x0F0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_with_font_overlapping_program() {
+        Ram::with_font(&FONT, 0x1D0);
+    }
+
+    #[test]
+    fn test_read_word() {
+        let ram = Ram::from_bytes(&[0xA2, 0x2A]).expect("Error loading ROM");
+        assert_eq!(ram.read_word(0x200), Ok(0xA22A));
+    }
+
+    #[test]
+    fn test_read_word_last_address() {
+        let mut ram = Ram::empty();
+        ram.memory[0xFFE] = 0x12;
+        ram.memory[0xFFF] = 0x34;
+        ram.memory[0x000] = 0x56;
+        assert_eq!(ram.read_word(0xFFE), Ok(0x1234));
+        assert_eq!(ram.read_word(0xFFF), Err(MemoryError::OutOfBounds { address: 0x1000 }));
+        ram.set_address_policy(AddressPolicy::Wrap);
+        assert_eq!(ram.read_word(0xFFF), Ok(0x3456));
+    }
+
+    #[test]
+    fn test_read_word_max_address() {
+        let mut ram = Ram::empty();
+        assert_eq!(ram.read_word(0xFFFF), Err(MemoryError::OutOfBounds { address: 0xFFFF }));
+        ram.memory[0xFFF] = 0x12;
+        ram.memory[0x000] = 0x34;
+        ram.set_address_policy(AddressPolicy::Wrap);
+        assert_eq!(ram.read_word(0xFFFF), Ok(0x1234));
+    }
+
+    #[test]
+    fn test_read_u8() {
+        let mut ram = Ram::empty();
+        ram.memory[0xFFF] = 0x42;
+        assert_eq!(ram.read_u8(0x000), Ok(0x00));
+        assert_eq!(ram.read_u8(0xFFF), Ok(0x42));
+        assert_eq!(ram.read_u8(0x1000), Err(MemoryError::OutOfBounds { address: 0x1000 }));
+        ram.set_address_policy(AddressPolicy::Wrap);
+        assert_eq!(ram.read_u8(0x1FFF), Ok(0x42));
+    }
+
+    #[test]
+    fn test_write_u8() {
+        let mut ram = Ram::empty();
+        ram.write_u8(0xFFF, 0x42).expect("Error writing memory");
+        assert_eq!(ram.memory[0xFFF], 0x42);
+        assert_eq!(ram.write_u8(0x1000, 0x43), Err(MemoryError::OutOfBounds { address: 0x1000 }));
+        ram.set_address_policy(AddressPolicy::Wrap);
+        ram.write_u8(0x1200, 0x43).expect("Error writing memory");
+        assert_eq!(ram.memory[0x200], 0x43);
+    }
+
+    #[test]
+    fn test_read_slice() {
+        let ram = Ram::from_bytes(&[0x01, 0x02, 0x03]).expect("Error loading ROM");
+        assert_eq!(ram.read_slice(0x200, 3), Ok(&[0x01, 0x02, 0x03][..]));
+        assert_eq!(ram.read_slice(0xFFC, 4).map(|s| s.len()), Ok(4));
+        assert_eq!(ram.read_slice(0xFFC, 5), Err(MemoryError::OutOfBounds { address: 0x1000 }));
+        assert_eq!(ram.read_slice(0x1000, 0), Err(MemoryError::OutOfBounds { address: 0x1000 }));
+    }
+
+    #[test]
+    fn test_read_slice_wraps_start() {
+        let mut ram = Ram::from_bytes(&[0x01, 0x02]).expect("Error loading ROM");
+        ram.set_address_policy(AddressPolicy::Wrap);
+        assert_eq!(ram.read_slice(0x1200, 2), Ok(&[0x01, 0x02][..]));
+        assert!(ram.read_slice(0xFFF, 2).is_err());
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        let ram = Ram::from_bytes(b"CHIP-8\x00\x01").expect("Error loading ROM");
+        let dump = ram.hex_dump(0x200..0x212);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "0200: 43 48 49 50 2D 38 00 01 00 00 00 00 00 00 00 00  |CHIP-8..........|");
+        assert_eq!(lines[1], "0210: 00 00                                            |..|");
+        assert_eq!(ram.hex_dump(0xFF8..0x2000).lines().count(), 1);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut ram = Ram::from_bytes(&[0x12, 0x34, 0x56]).expect("Error loading ROM");
+        ram.add_watchpoint(Watchpoint { range: 0x201..0x203, access: Access::Read, action: WatchAction::Log });
+        ram.add_watchpoint(Watchpoint { range: 0x300..0x301, access: Access::Write, action: WatchAction::Stop });
+        ram.add_watchpoint(Watchpoint { range: 0x200..0x202, access: Access::Execute, action: WatchAction::Log });
+
+        ram.read_u8(0x200).expect("Error reading memory");
+        ram.read_word(0x201).expect("Error reading memory");
+        assert!(ram.take_hits().is_empty());
+
+        ram.read_slice(0x200, 3).expect("Error reading memory");
+        ram.write_u8(0x300, 0xAB).expect("Error writing memory");
+        ram.write_u8(0x301, 0xCD).expect("Error writing memory");
+        ram.fetch_word(0x200).expect("Error reading memory");
+        assert_eq!(ram.take_hits(), [
+            Hit { access: Access::Read, address: 0x201, value: 0x34, action: WatchAction::Log },
+            Hit { access: Access::Read, address: 0x202, value: 0x56, action: WatchAction::Log },
+            Hit { access: Access::Write, address: 0x300, value: 0xAB, action: WatchAction::Stop },
+            Hit { access: Access::Execute, address: 0x200, value: 0x1234, action: WatchAction::Log },
+        ]);
+        assert!(ram.take_hits().is_empty());
+
+        assert_eq!(ram.remove_watchpoints(0x201), 2);
+        assert_eq!(ram.watchpoints().len(), 1);
+        ram.clear_watchpoints();
+        assert!(ram.watchpoints().is_empty());
+    }
+
+    #[test]
+    fn test_watchpoint_on_wrapped_address() {
+        let mut ram = Ram::empty();
+        ram.set_address_policy(AddressPolicy::Wrap);
+        ram.add_watchpoint(Watchpoint { range: 0x000..0x001, access: Access::Write, action: WatchAction::Log });
+        ram.write_u8(0x1000, 0x11).expect("Error writing memory");
+        assert_eq!(ram.take_hits()[0].address, 0x000);
+    }
+
+    #[test]
+    fn test_read_state() {
+        let ram = Ram::from_bytes(&[0x12, 0x00]).expect("Error loading ROM");
+        let mut state = StateWriter::new();
+        ram.write_state(&mut state);
+        let data = state.into_bytes();
+        let restored = Ram::read_state(&mut StateReader::new(&data)).expect("Error reading state");
+        assert_eq!(restored.font_address(), FONT_ADDRESS);
+        assert_eq!(restored.read_word(0x200), Ok(0x1200));
+
+        // Size, then font address and program start
+        let mut bad_font = data.clone();
+        bad_font[4..6].copy_from_slice(&0xFFF0u16.to_le_bytes());
+        let result = Ram::read_state(&mut StateReader::new(&bad_font));
+        assert_eq!(result.err(), Some(StateError::Corrupt("invalid font address")));
+        bad_font[4..6].copy_from_slice(&0x0F0u16.to_le_bytes());
+        let result = Ram::read_state(&mut StateReader::new(&bad_font));
+        assert_eq!(result.err(), Some(StateError::Corrupt("invalid font address")));
+        let mut bad_start = data.clone();
+        bad_start[6..8].copy_from_slice(&0x1000u16.to_le_bytes());
+        let result = Ram::read_state(&mut StateReader::new(&bad_start));
+        assert_eq!(result.err(), Some(StateError::Corrupt("invalid program start")));
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use crate::cpu::CpuError;
+use crate::machine::Machine;
+use crate::state::StateError;
+
+// One snapshot every 10 frames for 100 seconds at 60 Hz
+pub const DEFAULT_REWIND_INTERVAL: u64 = 10;
+pub const DEFAULT_REWIND_CAPACITY: usize = 600;
+
+#[derive(PartialEq, Debug)]
+pub enum RewindError {
+    NoHistory,
+    State(StateError),
+    Cpu(CpuError),
+}
+
+impl fmt::Display for RewindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RewindError::NoHistory => write!(f, "no history to rewind to"),
+            RewindError::State(err) => write!(f, "error restoring snapshot: {}", err),
+            RewindError::Cpu(err) => write!(f, "error replaying to the target: {}", err),
+        }
+    }
+}
+
+impl Error for RewindError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RewindError::State(err) => Some(err),
+            RewindError::Cpu(err) => Some(err),
+            RewindError::NoHistory => None,
+        }
+    }
+}
+
+impl From<StateError> for RewindError {
+    fn from(err: StateError) -> Self {
+        RewindError::State(err)
+    }
+}
+
+impl From<CpuError> for RewindError {
+    fn from(err: CpuError) -> Self {
+        RewindError::Cpu(err)
+    }
+}
+
+struct Snapshot {
+    frames: u64,
+    cycles: u64,
+    // A full save state for the newest snapshot, for the others the delta
+    // that turns the snapshot after them back into them
+    data: Vec<u8>,
+}
+
+// Ring buffer of save states taken every few frames. Going back to a point
+// between two snapshots restores the earlier one and replays from there,
+// which is exact as long as nothing outside the machine changed it in
+// between, so callers should take a snapshot after poking at the machine.
+pub struct Rewind {
+    interval: u64,
+    capacity: usize,
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Rewind::new(DEFAULT_REWIND_INTERVAL, DEFAULT_REWIND_CAPACITY)
+    }
+}
+
+impl Rewind {
+    pub fn new(interval: u64, capacity: usize) -> Self {
+        assert!(interval > 0, "Snapshots need at least one frame between them");
+        assert!(capacity > 0, "The rewind buffer needs room for a snapshot");
+        Rewind { interval, capacity, snapshots: VecDeque::new() }
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    // Cycle of the oldest snapshot, as far back as the buffer can go
+    pub fn oldest_cycle(&self) -> Option<u64> {
+        self.snapshots.front().map(|snapshot| snapshot.cycles)
+    }
+
+    // Bytes used by the snapshots
+    pub fn memory_usage(&self) -> usize {
+        self.snapshots.iter().map(|snapshot| snapshot.data.len()).sum()
+    }
+
+    // Takes a snapshot if the interval has passed since the last one, meant
+    // to be called after every step
+    pub fn record(&mut self, machine: &Machine) {
+        self.discard_stale(machine);
+        match self.snapshots.back() {
+            Some(last) if machine.frames() < last.frames + self.interval => (),
+            _ => self.snapshot(machine),
+        }
+    }
+
+    // The machine went back in time without us, by loading an earlier state
+    // say, so the history belongs to another run
+    fn discard_stale(&mut self, machine: &Machine) {
+        if self.snapshots.back().is_some_and(|last| last.cycles > machine.cycles()) {
+            self.clear();
+        }
+    }
+
+    pub fn snapshot(&mut self, machine: &Machine) {
+        let state = machine.save_state();
+        if let Some(last) = self.snapshots.back_mut() {
+            last.data = diff(&state, &last.data);
+        }
+        self.snapshots.push_back(Snapshot { frames: machine.frames(), cycles: machine.cycles(), data: state });
+        if self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    // Goes back the number of frames, or as far as the buffer allows,
+    // returning how many frames were rewound
+    pub fn rewind(&mut self, machine: &mut Machine, frames: u64) -> Result<u64, RewindError> {
+        self.discard_stale(machine);
+        let current = machine.frames();
+        let target = current.saturating_sub(frames);
+        self.restore(machine, |snapshot| snapshot.frames <= target)?;
+        replay(machine, |machine| machine.frames() >= target)?;
+        Ok(current - machine.frames())
+    }
+
+    // Undoes the last steps, stalled ones included, or as many as the buffer
+    // allows, returning how many were undone
+    pub fn step_back(&mut self, machine: &mut Machine, cycles: u64) -> Result<u64, RewindError> {
+        self.discard_stale(machine);
+        let current = machine.cycles();
+        let target = current.saturating_sub(cycles);
+        self.restore(machine, |snapshot| snapshot.cycles <= target)?;
+        replay(machine, |machine| machine.cycles() >= target)?;
+        Ok(current - machine.cycles())
+    }
+
+    // Loads the newest snapshot matching the predicate, or the oldest one if
+    // none do, and forgets the snapshots after it
+    fn restore<F>(&mut self, machine: &mut Machine, predicate: F) -> Result<(), RewindError>
+    where
+        F: Fn(&Snapshot) -> bool,
+    {
+        let newest = self.snapshots.back().ok_or(RewindError::NoHistory)?;
+        let index = self.snapshots.iter().rposition(predicate).unwrap_or(0);
+        let mut state = newest.data.clone();
+        for snapshot in self.snapshots.iter().skip(index).rev().skip(1) {
+            state = patch(&state, &snapshot.data)?;
+        }
+        machine.load_state(&state)?;
+        self.snapshots.truncate(index + 1);
+        self.snapshots[index].data = state;
+        Ok(())
+    }
+}
+
+// Runs the machine forward again without tracing or reporting watchpoints,
+// since they already fired the first time around
+fn replay<F>(machine: &mut Machine, predicate: F) -> Result<(), CpuError>
+where
+    F: Fn(&Machine) -> bool,
+{
+    let tracer = machine.take_tracer();
+    let mut result = Ok(());
+    while result.is_ok() && !predicate(machine) {
+        result = machine.step();
+    }
+    machine.take_watch_events();
+    machine.take_watch_stop();
+    if let Some(tracer) = tracer {
+        machine.set_tracer(tracer);
+    }
+    result
+}
+
+fn push_varint(data: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<usize, StateError> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or(StateError::Truncated)?;
+        *data = rest;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(StateError::Corrupt("invalid delta"))
+}
+
+// Encodes the target as the bytes that differ from the base: its length,
+// then runs of bytes to copy from the base followed by bytes to replace them
+fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    push_varint(&mut delta, target.len());
+    let same = |i: usize| base.get(i) == Some(&target[i]);
+    let mut i = 0;
+    while i < target.len() {
+        let start = i;
+        while i < target.len() && same(i) {
+            i += 1;
+        }
+        let changed = i;
+        while i < target.len() && !same(i) {
+            i += 1;
+        }
+        push_varint(&mut delta, changed - start);
+        push_varint(&mut delta, i - changed);
+        delta.extend_from_slice(&target[changed..i]);
+    }
+    delta
+}
+
+fn patch(base: &[u8], mut delta: &[u8]) -> Result<Vec<u8>, StateError> {
+    let len = read_varint(&mut delta)?;
+    let mut target = Vec::with_capacity(len);
+    while target.len() < len {
+        let copied = read_varint(&mut delta)?;
+        let start = target.len();
+        let copy = base.get(start..start + copied).ok_or(StateError::Corrupt("invalid delta"))?;
+        target.extend_from_slice(copy);
+        let replaced = read_varint(&mut delta)?;
+        if replaced > delta.len() {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = delta.split_at(replaced);
+        target.extend_from_slice(bytes);
+        delta = rest;
+    }
+    if target.len() != len || !delta.is_empty() {
+        return Err(StateError::Corrupt("invalid delta"));
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, patch, Rewind, RewindError};
+    use crate::machine::Machine;
+    use crate::ram::Ram;
+
+    // LD V0, 0x00; ADD V0, 0x01; LD I, 0x300; LD [I], V0; JP 0x202
+    const ROM: [u8; 10] = [0x60, 0x00, 0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x02];
+
+    fn machine() -> Machine {
+        let mut machine = Machine::new(Ram::from_bytes(&ROM).expect("Error loading ROM"));
+        machine.set_instructions_per_frame(4);
+        machine
+    }
+
+    #[test]
+    fn test_diff_and_patch() {
+        let base = [1, 2, 3, 4, 5, 6, 7, 8];
+        let targets: [&[u8]; 5] = [&base, &[1, 9, 3, 4, 5, 6, 9, 9], &[0, 2, 3], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10], &[]];
+        for target in targets {
+            let delta = diff(&base, target);
+            assert_eq!(patch(&base, &delta).expect("Error applying delta"), target);
+        }
+        assert!(diff(&base, &base).len() < 4);
+        assert!(patch(&base, &[2, 0]).is_err());
+    }
+
+    #[test]
+    fn test_rewind_frames() {
+        let mut machine = machine();
+        let mut rewind = Rewind::new(2, 100);
+        let mut states = Vec::new();
+        for _ in 0..20 {
+            states.push(machine.save_state());
+            rewind.record(&machine);
+            machine.run_frame().expect("Error running the machine");
+        }
+        assert_eq!(rewind.len(), 10);
+        assert_eq!(rewind.rewind(&mut machine, 5), Ok(5));
+        assert_eq!(machine.frames(), 15);
+        assert_eq!(machine.save_state(), states[15]);
+        assert_eq!(rewind.rewind(&mut machine, 100), Ok(15));
+        assert_eq!(machine.save_state(), states[0]);
+        assert_eq!(rewind.len(), 1);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut machine = machine();
+        let mut rewind = Rewind::new(1, 3);
+        assert_eq!(rewind.step_back(&mut machine, 1), Err(RewindError::NoHistory));
+        let mut states = Vec::new();
+        for _ in 0..40 {
+            states.push(machine.save_state());
+            rewind.record(&machine);
+            machine.step().expect("Error running the machine");
+        }
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.oldest_cycle(), Some(28));
+        assert_eq!(rewind.step_back(&mut machine, 1), Ok(1));
+        assert_eq!(machine.save_state(), states[39]);
+        assert_eq!(machine.ram().read_u8(0x300), Ok(9));
+        // Only goes back as far as the oldest snapshot
+        assert_eq!(rewind.step_back(&mut machine, 50), Ok(11));
+        assert_eq!(machine.save_state(), states[28]);
+    }
+
+    #[test]
+    fn test_stale_history() {
+        let mut machine = machine();
+        let start = machine.save_state();
+        let mut rewind = Rewind::new(1, 10);
+        for _ in 0..5 {
+            rewind.record(&machine);
+            machine.run_frame().expect("Error running the machine");
+        }
+        machine.load_state(&start).expect("Error loading state");
+        assert_eq!(rewind.step_back(&mut machine, 1), Err(RewindError::NoHistory));
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.rewind(&mut machine, 1), Err(RewindError::NoHistory));
+        assert_eq!(machine.save_state(), start);
+    }
+}
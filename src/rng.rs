@@ -0,0 +1,91 @@
+// Source of the random bytes used by Cxkk
+pub trait RandomSource {
+    fn next_byte(&mut self) -> u8;
+
+    // Seed the source was started from, if it has one worth reporting
+    fn seed(&self) -> Option<u64> {
+        None
+    }
+
+    // Current position in the sequence, so save states can resume it
+    fn state(&self) -> Option<u64> {
+        None
+    }
+}
+
+pub const DEFAULT_SEED: u64 = 0;
+
+// SplitMix64, small and good enough for games, and fully determined by its seed
+pub struct SplitMix64 {
+    seed: u64,
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { seed, state: seed }
+    }
+
+    // Resumes a sequence from a saved state
+    pub fn from_state(seed: u64, state: u64) -> Self {
+        SplitMix64 { seed, state }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for SplitMix64 {
+    fn next_byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn seed(&self) -> Option<u64> {
+        Some(self.seed)
+    }
+
+    fn state(&self) -> Option<u64> {
+        Some(self.state)
+    }
+}
+
+impl Default for SplitMix64 {
+    fn default() -> Self {
+        SplitMix64::new(DEFAULT_SEED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RandomSource, SplitMix64};
+
+    #[test]
+    fn test_same_seed_same_sequence() {
+        let mut a = SplitMix64::new(42);
+        let mut b = SplitMix64::new(42);
+        let a: Vec<u8> = (0..32).map(|_| a.next_byte()).collect();
+        let b: Vec<u8> = (0..32).map(|_| b.next_byte()).collect();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_different_seed_different_sequence() {
+        let mut a = SplitMix64::new(1);
+        let mut b = SplitMix64::new(2);
+        let a: Vec<u8> = (0..32).map(|_| a.next_byte()).collect();
+        let b: Vec<u8> = (0..32).map(|_| b.next_byte()).collect();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_seed() {
+        let mut rng = SplitMix64::new(1234);
+        rng.next_byte();
+        assert_eq!(rng.seed(), Some(1234));
+    }
+}
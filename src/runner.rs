@@ -0,0 +1,225 @@
+use std::fmt;
+use crate::cpu::CpuError;
+use crate::disassembler::disassemble_at;
+use crate::instruction::Instruction;
+use crate::machine::Machine;
+use crate::movie::Player;
+use crate::tracer::escape;
+
+// When a headless run stops, whatever happens first
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Limit {
+    Cycles(u64),
+    Frames(u64),
+}
+
+// Why a headless run stopped
+#[derive(PartialEq, Debug)]
+pub enum Exit {
+    Halted,
+    Limit,
+    // A jump to itself, which programs use to stop
+    EndlessLoop(u16),
+    // Waiting on a key with no input left to give it
+    WaitingKey,
+    Error(CpuError),
+}
+
+impl Exit {
+    // Short name for the machine readable report
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exit::Halted => "halted",
+            Exit::Limit => "limit",
+            Exit::EndlessLoop(_) => "endless_loop",
+            Exit::WaitingKey => "waiting_key",
+            Exit::Error(_) => "error",
+        }
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exit::Halted => write!(f, "program exited"),
+            Exit::Limit => write!(f, "limit reached"),
+            Exit::EndlessLoop(addr) => write!(f, "endless loop at {:04X}", addr),
+            Exit::WaitingKey => write!(f, "waiting for a key press"),
+            Exit::Error(error) => write!(f, "error: {}", error),
+        }
+    }
+}
+
+// Runs the machine without a window until the limit, the program exits or
+// can't get any further, feeding it the movie's input if there is one
+pub fn run(machine: &mut Machine, mut player: Option<&mut Player>, limit: Limit) -> Exit {
+    loop {
+        if let Some(player) = player.as_deref_mut() {
+            player.apply(machine);
+        }
+        let reached = match limit {
+            Limit::Cycles(cycles) => machine.cycles() >= cycles,
+            Limit::Frames(frames) => machine.frames() >= frames,
+        };
+        if reached {
+            return Exit::Limit;
+        }
+        if machine.is_halted() {
+            return Exit::Halted;
+        }
+        // PC is already past Fx0A while it waits
+        let waiting = machine.cpu().is_waiting_key();
+        let pc = machine.cpu().pc();
+        let line = disassemble_at(machine.ram(), pc);
+        if !waiting && line.and_then(|line| line.instruction) == Some(Instruction::Jump(pc)) {
+            return Exit::EndlessLoop(pc);
+        }
+        if let Err(error) = machine.step() {
+            return Exit::Error(error);
+        }
+        // Checked across a step, as a key released by the last input can
+        // still finish the wait
+        let no_input = match player.as_deref() {
+            Some(player) => player.is_finished(),
+            None => true,
+        };
+        if waiting && machine.cpu().is_waiting_key() && no_input {
+            return Exit::WaitingKey;
+        }
+    }
+}
+
+// State of the machine at the end of a run
+#[derive(PartialEq, Debug)]
+pub struct Report {
+    pub exit: Exit,
+    pub pc: u16,
+    pub i: u16,
+    pub sp: u8,
+    pub stack: Vec<u16>,
+    pub registers: [u8; 16],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub cycles: u64,
+    pub frames: u64,
+    // The machine's own state dump, for the text report
+    pub dump: String,
+}
+
+impl Report {
+    pub fn new(machine: &Machine, exit: Exit) -> Self {
+        let cpu = machine.cpu();
+        Report {
+            exit,
+            pc: cpu.pc(),
+            i: cpu.i(),
+            sp: cpu.sp(),
+            stack: cpu.stack().to_vec(),
+            registers: *cpu.registers(),
+            delay_timer: cpu.delay_timer(),
+            sound_timer: cpu.sound_timer(),
+            cycles: machine.cycles(),
+            frames: machine.frames(),
+            dump: machine.to_string(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self.exit, Exit::Error(_))
+    }
+
+    pub fn to_text(&self) -> String {
+        format!("Exit: {}\n{}", self.exit, self.dump)
+    }
+
+    pub fn to_json(&self) -> String {
+        let error = match &self.exit {
+            Exit::Error(error) => format!("\"{}\"", escape(&error.to_string())),
+            _ => "null".to_string(),
+        };
+        let registers: Vec<String> = self.registers.iter().map(|value| value.to_string()).collect();
+        let stack: Vec<String> = self.stack.iter().map(|addr| addr.to_string()).collect();
+        format!(
+            "{{\"exit\":\"{}\",\"error\":{},\"pc\":{},\"i\":{},\"sp\":{},\"stack\":[{}],\"registers\":[{}],\
+             \"dt\":{},\"st\":{},\"cycles\":{},\"frames\":{}}}",
+            self.exit.name(), error, self.pc, self.i, self.sp, stack.join(","), registers.join(","),
+            self.delay_timer, self.sound_timer, self.cycles, self.frames
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, Exit, Limit, Report};
+    use crate::cpu::CpuError;
+    use crate::machine::Machine;
+    use crate::movie::{InputEvent, KeyAction, Movie, Player};
+    use crate::quirks::Quirks;
+    use crate::ram::Ram;
+    use crate::variant::Variant;
+
+    fn machine(rom: &[u8]) -> Machine {
+        Machine::new(Ram::from_bytes(rom).expect("Error loading ROM"))
+    }
+
+    #[test]
+    fn test_stops() {
+        // ADD V0, 1; JP 0x200
+        let mut counter = machine(&[0x70, 0x01, 0x12, 0x00]);
+        assert_eq!(run(&mut counter, None, Limit::Cycles(7)), Exit::Limit);
+        assert_eq!(counter.cycles(), 7);
+        assert_eq!(run(&mut counter, None, Limit::Frames(2)), Exit::Limit);
+        assert_eq!(counter.frames(), 2);
+
+        // LD V3, 0x01; JP 0x202
+        assert_eq!(run(&mut machine(&[0x63, 0x01, 0x12, 0x02]), None, Limit::Frames(10)), Exit::EndlessLoop(0x202));
+        // LD VF, K
+        assert_eq!(run(&mut machine(&[0xFF, 0x0A]), None, Limit::Frames(10)), Exit::WaitingKey);
+        assert_eq!(
+            run(&mut machine(&[0x00, 0xEE]), None, Limit::Frames(10)),
+            Exit::Error(CpuError::StackUnderflow)
+        );
+        let mut exits = Machine::for_variant(Variant::SuperChip, &[0x00, 0xFD]).expect("Error loading ROM");
+        assert_eq!(run(&mut exits, None, Limit::Frames(10)), Exit::Halted);
+    }
+
+    #[test]
+    fn test_movie_input() {
+        // LD V5, K; JP 0x202
+        let rom = [0xF5, 0x0A, 0x12, 0x02];
+        let mut movie = Movie::new(Variant::Chip8, Quirks::cosmac_vip(), 0, 10);
+        movie.push(InputEvent { frame: 3, key: 0x7, action: KeyAction::Press });
+        movie.push(InputEvent { frame: 4, key: 0x7, action: KeyAction::Release });
+        let mut machine = movie.machine(&rom).expect("Error loading ROM");
+        let mut player = Player::new(movie);
+        assert_eq!(run(&mut machine, Some(&mut player), Limit::Frames(10)), Exit::EndlessLoop(0x202));
+        assert_eq!(machine.cpu().registers()[5], 0x7);
+        assert_eq!(machine.frames(), 4);
+    }
+
+    #[test]
+    fn test_report() {
+        // LD V1, 0x22; CALL 0x206; JP 0x204; RET
+        let mut machine = machine(&[0x61, 0x22, 0x22, 0x06, 0x12, 0x04, 0x00, 0xEE]);
+        machine.run_cycles(2).expect("Error running the machine");
+        let report = Report::new(&machine, Exit::Limit);
+        assert!(!report.is_error());
+        assert_eq!(report.to_text(), "\
+Exit: limit reached
+PC: 0206  I: 0000  SP: 1  DT: 00  ST: 00
+V0: 00  V1: 22  V2: 00  V3: 00  V4: 00  V5: 00  V6: 00  V7: 00
+V8: 00  V9: 00  VA: 00  VB: 00  VC: 00  VD: 00  VE: 00  VF: 00
+Stack: 0204
+Seed: 0
+Cycles: 2  Frames: 0");
+        assert_eq!(
+            report.to_json(),
+            "{\"exit\":\"limit\",\"error\":null,\"pc\":518,\"i\":0,\"sp\":1,\"stack\":[516],\
+             \"registers\":[0,34,0,0,0,0,0,0,0,0,0,0,0,0,0,0],\"dt\":0,\"st\":0,\"cycles\":2,\"frames\":0}"
+        );
+
+        let report = Report::new(&machine, Exit::Error(CpuError::InvalidOpcode { opcode: 0x5001, pc: 0x206 }));
+        assert!(report.is_error());
+        assert!(report.to_json().contains("\"exit\":\"error\",\"error\":\"invalid opcode 5001 at 0206\""));
+    }
+}
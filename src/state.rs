@@ -0,0 +1,251 @@
+use std::error::Error;
+use std::fmt;
+use crate::audio::{Audio, PATTERN_SIZE};
+use crate::keypad::{Keypad, KEY_COUNT};
+use crate::quirks::{IndexIncrement, Quirks};
+use crate::variant::Variant;
+
+// Save states start with this, then the format version and the variant
+pub const STATE_MAGIC: [u8; 4] = *b"C8ST";
+pub const STATE_VERSION: u16 = 1;
+
+#[derive(PartialEq, Debug)]
+pub enum StateError {
+    NotASaveState,
+    UnsupportedVersion { version: u16 },
+    VariantMismatch { expected: Variant, found: Variant },
+    Truncated,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotASaveState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion { version } => {
+                write!(f, "save state version {} is not supported, expected {}", version, STATE_VERSION)
+            }
+            StateError::VariantMismatch { expected, found } => {
+                write!(f, "save state is for {}, but the machine runs {}", found, expected)
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Corrupt(what) => write!(f, "save state is corrupt: {}", what),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+// Little-endian encoding of the state of each component
+#[derive(Default)]
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn variant(&mut self, variant: Variant) {
+        self.u8(match variant {
+            Variant::Chip8 => 0,
+            Variant::SuperChip => 1,
+            Variant::XoChip => 2,
+        });
+    }
+
+    pub fn quirks(&mut self, quirks: &Quirks) {
+        self.bool(quirks.shift_uses_vy);
+        self.bool(quirks.jump_uses_vx);
+        self.bool(quirks.logic_resets_vf);
+        self.u8(match quirks.increment_index {
+            IndexIncrement::None => 0,
+            IndexIncrement::ByX => 1,
+            IndexIncrement::ByXPlusOne => 2,
+        });
+        for quirk in [
+            quirks.index_overflow_sets_vf,
+            quirks.clip_sprites,
+            quirks.display_wait,
+        ] {
+            self.bool(quirk);
+        }
+    }
+
+    pub fn keypad(&mut self, keypad: &Keypad) {
+        let mask = keypad.keys().iter().enumerate().fold(0, |mask, (key, &pressed)| mask | (pressed as u16) << key);
+        self.u16(mask);
+    }
+
+    pub fn audio(&mut self, audio: &Audio) {
+        self.bytes(audio.pattern());
+        self.u8(audio.pitch());
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if len > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt("invalid flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn variant(&mut self) -> Result<Variant, StateError> {
+        match self.u8()? {
+            0 => Ok(Variant::Chip8),
+            1 => Ok(Variant::SuperChip),
+            2 => Ok(Variant::XoChip),
+            _ => Err(StateError::Corrupt("unknown variant")),
+        }
+    }
+
+    pub fn quirks(&mut self) -> Result<Quirks, StateError> {
+        Ok(Quirks {
+            shift_uses_vy: self.bool()?,
+            jump_uses_vx: self.bool()?,
+            logic_resets_vf: self.bool()?,
+            increment_index: match self.u8()? {
+                0 => IndexIncrement::None,
+                1 => IndexIncrement::ByX,
+                2 => IndexIncrement::ByXPlusOne,
+                _ => return Err(StateError::Corrupt("unknown index increment")),
+            },
+            index_overflow_sets_vf: self.bool()?,
+            clip_sprites: self.bool()?,
+            display_wait: self.bool()?,
+        })
+    }
+
+    pub fn keypad(&mut self) -> Result<Keypad, StateError> {
+        let mask = self.u16()?;
+        let mut keypad = Keypad::new();
+        for key in 0..KEY_COUNT as u8 {
+            if mask & 1 << key != 0 {
+                keypad.press(key);
+            }
+        }
+        Ok(keypad)
+    }
+
+    pub fn audio(&mut self) -> Result<Audio, StateError> {
+        let mut audio = Audio::new();
+        audio.set_pattern(self.array::<PATTERN_SIZE>()?);
+        audio.set_pitch(self.u8()?);
+        Ok(audio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StateError, StateReader, StateWriter};
+    use crate::keypad::Keypad;
+    use crate::quirks::Quirks;
+    use crate::variant::Variant;
+
+    #[test]
+    fn test_round_trip() {
+        let mut keypad = Keypad::new();
+        keypad.press(0x0);
+        keypad.press(0xF);
+        let mut writer = StateWriter::new();
+        writer.u8(0x12);
+        writer.u16(0x3456);
+        writer.u64(u64::MAX - 1);
+        writer.variant(Variant::XoChip);
+        writer.quirks(&Quirks::chip48());
+        writer.keypad(&keypad);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.u64(), Ok(u64::MAX - 1));
+        assert_eq!(reader.variant(), Ok(Variant::XoChip));
+        assert_eq!(reader.quirks(), Ok(Quirks::chip48()));
+        let restored = reader.keypad().expect("Error reading keypad");
+        assert_eq!(restored.keys(), keypad.keys());
+        assert!(reader.is_empty());
+        assert_eq!(reader.u8(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        assert_eq!(StateReader::new(&[2]).bool(), Err(StateError::Corrupt("invalid flag")));
+        assert_eq!(StateReader::new(&[3]).variant(), Err(StateError::Corrupt("unknown variant")));
+        assert_eq!(StateReader::new(&[1, 2, 3]).u32(), Err(StateError::Truncated));
+    }
+}
@@ -0,0 +1,243 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::instruction::Instruction;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum TraceFormat {
+    // One aligned line per instruction, easy to diff
+    #[default]
+    Text,
+    // One JSON object per line
+    JsonLines,
+}
+
+// State after one executed instruction. An Fx0A gets a second entry when
+// the key wait ends and it loads the register.
+#[derive(PartialEq, Clone, Debug)]
+pub struct TraceEntry {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    // Second word of XO-CHIP's `F000 NNNN`
+    pub operand: Option<u16>,
+    // None for opcodes that don't decode
+    pub instruction: Option<Instruction>,
+    // Registers whose value changed, with the new value
+    pub changed: Vec<(u8, u8)>,
+    pub i: u16,
+    pub sp: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    // Set when the instruction stopped the machine
+    pub error: Option<String>,
+}
+
+impl TraceEntry {
+    pub fn to_text(&self) -> String {
+        let raw = match self.operand {
+            Some(operand) => format!("{:04X} {:04X}", self.opcode, operand),
+            None => format!("{:04X}", self.opcode),
+        };
+        let instruction = self.instruction.map_or_else(|| format!("DW 0x{:04X}", self.opcode), |i| i.to_string());
+        let mut line = format!(
+            "{:>8} {:04X}: {:<9}  {:<20} I={:04X} SP={:X} DT={:02X} ST={:02X}",
+            self.cycle, self.pc, raw, instruction,
+            self.i, self.sp, self.delay_timer, self.sound_timer
+        );
+        for (reg, value) in &self.changed {
+            line.push_str(&format!(" V{:X}={:02X}", reg, value));
+        }
+        if let Some(error) = &self.error {
+            line.push_str(&format!(" error: {}", error));
+        }
+        line
+    }
+
+    pub fn to_json(&self) -> String {
+        let operand = self.operand.map_or("null".to_string(), |operand| operand.to_string());
+        let instruction = self.instruction.map_or("null".to_string(), |i| format!("\"{}\"", i));
+        let error = self.error.as_ref().map_or("null".to_string(), |error| format!("\"{}\"", escape(error)));
+        let changed: Vec<String> = self.changed.iter().map(|(reg, value)| format!("\"V{:X}\":{}", reg, value)).collect();
+        format!(
+            "{{\"cycle\":{},\"pc\":{},\"opcode\":{},\"operand\":{},\"instruction\":{},\"changed\":{{{}}},\
+             \"i\":{},\"sp\":{},\"dt\":{},\"st\":{},\"error\":{}}}",
+            self.cycle, self.pc, self.opcode, operand, instruction, changed.join(","),
+            self.i, self.sp, self.delay_timer, self.sound_timer, error
+        )
+    }
+}
+
+// Quotes and control characters escaped for a JSON string
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Writes trace entries as the machine runs. Writing never interrupts the
+// machine, the first I/O error stops the trace and is returned by `finish`.
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    format: TraceFormat,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new<W: Write + 'static>(writer: W, format: TraceFormat) -> Self {
+        Tracer { writer: Box::new(writer), format, error: None }
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P, format: TraceFormat) -> io::Result<Self> {
+        Ok(Tracer::new(BufWriter::new(File::create(path)?), format))
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    pub fn record(&mut self, entry: &TraceEntry) {
+        if self.error.is_some() {
+            return;
+        }
+        let line = match self.format {
+            TraceFormat::Text => entry.to_text(),
+            TraceFormat::JsonLines => entry.to_json(),
+        };
+        if let Err(error) = writeln!(self.writer, "{}", line) {
+            self.error = Some(error);
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+// Writer shared by the tests of everything that traces
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    // Lets the test read what the tracer wrote
+    #[derive(Clone, Default)]
+    pub(crate) struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Buffer {
+        pub(crate) fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).expect("Trace isn't UTF-8")
+        }
+    }
+
+    impl Write for Buffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use super::testing::Buffer;
+    use super::{TraceEntry, TraceFormat, Tracer};
+    use crate::instruction::Instruction;
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn entry() -> TraceEntry {
+        TraceEntry {
+            cycle: 3,
+            pc: 0x204,
+            opcode: 0x8426,
+            operand: None,
+            instruction: Some(Instruction::ShiftRight(4, 2)),
+            changed: vec![(0x4, 0x02), (0xF, 0x01)],
+            i: 0x300,
+            sp: 1,
+            delay_timer: 0x3C,
+            sound_timer: 0,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(
+            entry().to_text(),
+            "       3 0204: 8426       SHR V4, V2           I=0300 SP=1 DT=3C ST=00 V4=02 VF=01"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            entry().to_json(),
+            "{\"cycle\":3,\"pc\":516,\"opcode\":33830,\"operand\":null,\"instruction\":\"SHR V4, V2\",\
+             \"changed\":{\"V4\":2,\"VF\":1},\"i\":768,\"sp\":1,\"dt\":60,\"st\":0,\"error\":null}"
+        );
+    }
+
+    #[test]
+    fn test_error() {
+        let entry = TraceEntry {
+            opcode: 0x5001,
+            instruction: None,
+            changed: Vec::new(),
+            error: Some("invalid opcode 5001 at 0204".to_string()),
+            ..entry()
+        };
+        assert_eq!(
+            entry.to_text(),
+            "       3 0204: 5001       DW 0x5001            I=0300 SP=1 DT=3C ST=00 error: invalid opcode 5001 at 0204"
+        );
+        assert!(entry.to_json().contains("\"instruction\":null,"));
+        assert!(entry.to_json().ends_with("\"error\":\"invalid opcode 5001 at 0204\"}"));
+    }
+
+    #[test]
+    fn test_record() {
+        let buffer = Buffer::default();
+        let mut tracer = Tracer::new(buffer.clone(), TraceFormat::JsonLines);
+        tracer.record(&entry());
+        tracer.record(&entry());
+        tracer.finish().expect("Error finishing trace");
+        let output = buffer.contents();
+        assert_eq!(output.lines().count(), 2);
+        assert!(output.lines().all(|line| line == entry().to_json()));
+    }
+
+    #[test]
+    fn test_record_error() {
+        let mut tracer = Tracer::new(Broken, TraceFormat::Text);
+        tracer.record(&entry());
+        tracer.record(&entry());
+        assert_eq!(tracer.finish().map_err(|error| error.to_string()), Err("disk full".to_string()));
+    }
+}
@@ -0,0 +1,128 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use crate::instruction::Instruction;
+use crate::quirks::Quirks;
+use crate::ram::{RAM_SIZE, XO_RAM_SIZE};
+
+// The interpreter being emulated, which decides the instruction set, memory
+// size and default quirks
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Variant {
+    #[default]
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Variant {
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Variant::Chip8 => Quirks::cosmac_vip(),
+            Variant::SuperChip => Quirks::super_chip(),
+            Variant::XoChip => Quirks::xo_chip(),
+        }
+    }
+
+    pub fn memory_size(self) -> usize {
+        match self {
+            Variant::Chip8 | Variant::SuperChip => RAM_SIZE,
+            Variant::XoChip => XO_RAM_SIZE,
+        }
+    }
+
+    pub fn supports(self, instruction: &Instruction) -> bool {
+        match instruction {
+            Instruction::ScrollDown(_)
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Exit
+            | Instruction::LowRes
+            | Instruction::HighRes
+            | Instruction::LoadBigSprite(_)
+            | Instruction::StoreFlags(_)
+            | Instruction::LoadFlags(_) => self != Variant::Chip8,
+            Instruction::ScrollUp(_)
+            | Instruction::SaveRange(..)
+            | Instruction::LoadRange(..)
+            | Instruction::LoadLongIndex(_)
+            | Instruction::SelectPlanes(_)
+            | Instruction::LoadAudio
+            | Instruction::SetPitch(_) => self == Variant::XoChip,
+            _ => true,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ParseVariantError(String);
+
+impl fmt::Display for ParseVariantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown variant '{}', expected chip8, schip or xochip", self.0)
+    }
+}
+
+impl Error for ParseVariantError {}
+
+// Lowercase without separators, so "XO-CHIP", "xo_chip" and "xochip" match
+pub(crate) fn normalize_name(name: &str) -> String {
+    name.to_ascii_lowercase().replace(['-', '_'], "")
+}
+
+impl FromStr for Variant {
+    type Err = ParseVariantError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match normalize_name(name).as_str() {
+            "chip8" => Ok(Variant::Chip8),
+            "schip" | "superchip" => Ok(Variant::SuperChip),
+            "xo" | "xochip" => Ok(Variant::XoChip),
+            _ => Err(ParseVariantError(name.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Variant::Chip8 => "chip8",
+            Variant::SuperChip => "schip",
+            Variant::XoChip => "xochip",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Variant;
+    use crate::instruction::Instruction;
+
+    #[test]
+    fn test_supports() {
+        assert!(Variant::Chip8.supports(&Instruction::Draw(0, 0, 5)));
+        assert!(!Variant::Chip8.supports(&Instruction::HighRes));
+        assert!(Variant::SuperChip.supports(&Instruction::HighRes));
+        assert!(!Variant::SuperChip.supports(&Instruction::LoadLongIndex(0x1234)));
+        assert!(Variant::XoChip.supports(&Instruction::HighRes));
+        assert!(Variant::XoChip.supports(&Instruction::LoadLongIndex(0x1234)));
+    }
+
+    #[test]
+    fn test_memory_size() {
+        assert_eq!(Variant::Chip8.memory_size(), 4096);
+        assert_eq!(Variant::SuperChip.memory_size(), 4096);
+        assert_eq!(Variant::XoChip.memory_size(), 65536);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("CHIP-8".parse(), Ok(Variant::Chip8));
+        assert_eq!("super-chip".parse(), Ok(Variant::SuperChip));
+        assert_eq!("xo_chip".parse(), Ok(Variant::XoChip));
+        assert!("chip-9".parse::<Variant>().is_err());
+        for variant in [Variant::Chip8, Variant::SuperChip, Variant::XoChip] {
+            assert_eq!(variant.to_string().parse(), Ok(variant));
+        }
+    }
+}